@@ -1,6 +1,10 @@
 use bevy::prelude::*;
-use collisions::collider::{Collider, MeshCollider};
-use nalgebra::Vector3;
+use character::CharacterController;
+use collisions::collider::{Collider, MeshColliderMode, OneWayPlatform, SceneCollider, VHACDParameters};
+use fields::{Falloff, ForceField, ForceFieldKind};
+use fluids::FluidVolume;
+use joints::Joint;
+use vehicle::{Vehicle, Wheel};
 use rand::Rng;
 
 use crate::physics::*;
@@ -14,8 +18,17 @@ impl Plugin for LevelPlugin {
                 spawn_objects,
                 spawn_cubes,
                 spawn_concave_obj,
-                spawn_test_plat
-            ));
+                spawn_test_plat,
+                spawn_chair,
+                spawn_chain,
+                spawn_spring,
+                spawn_pool,
+                spawn_vortex,
+                spawn_one_way_platform,
+                spawn_vehicle,
+                spawn_planet
+            ))
+            .add_systems(Update, (drive_vehicles, walk_characters));
     }
 }
 
@@ -26,13 +39,7 @@ fn spawn_cubes(
     mut materials: ResMut<Assets<StandardMaterial>>,
 ) {
 
-    let shape_box_1 = Box::new(
-        parry3d::shape::Cuboid::new(Vector3::new(0.5, 0.5, 0.5))
-    );
-
-    let collider_1 = Collider {
-        shape: shape_box_1,
-    };
+    let collider = Collider::cuboid(Vec3::splat(0.5));
 
     commands.spawn((
         Mesh3d(meshes.add(Cuboid::new(1., 1., 1.))),
@@ -41,17 +48,9 @@ fn spawn_cubes(
         RigidBody::Dynamic,
         Velocity(Vec3::ZERO),
         Mass(1.),
-        collider_1
+        collider.clone()
     ));
 
-    let shape_box_2 = Box::new(
-        parry3d::shape::Cuboid::new(Vector3::new(0.5, 0.5, 0.5))
-    );
-
-    let collider_2 = Collider {
-        shape: shape_box_2,
-    };
-
     commands.spawn((
         Mesh3d(meshes.add(Cuboid::new(1., 1., 1.))),
         MeshMaterial3d(materials.add(Color::linear_rgb(1., 1., 0.))),
@@ -59,7 +58,7 @@ fn spawn_cubes(
         RigidBody::Dynamic,
         Velocity(Vec3::ZERO),
         Mass(3.),
-        collider_2
+        collider
     ));
 
 }
@@ -76,15 +75,11 @@ fn spawn_objects(
 
     let num = 1000;
 
+    // all the cubes share the same shape
+    let collider = Collider::cuboid(Vec3::splat(0.5));
+
     // spawn cubes
     for _ in 0..num {
-        let shape_box = Box::new(
-            parry3d::shape::Cuboid::new(Vector3::new(0.5, 0.5, 0.5))
-        );
-    
-        let collider_1 = Collider {
-            shape: shape_box,
-        };
     
         let random_color = Color::linear_rgb(
             rng.gen_range(0..100) as f32 / 100., 
@@ -104,8 +99,7 @@ fn spawn_objects(
             Transform::from_translation(random_pos),
             RigidBody::Dynamic,
             Velocity(Vec3::ZERO),
-            Mass(1.),
-            collider_1
+            collider.clone()
         ));
     
 
@@ -122,9 +116,11 @@ fn spawn_concave_obj(
     mut commands: Commands,
 ) {
 
-    let collider_shape = assets.load("./concave.glb#Mesh0/Primitive0"); 
-
-    let mesh_collider = MeshCollider(collider_shape);
+    // decomposed into convex parts so it can collide as a dynamic body
+    let scene_collider = SceneCollider {
+        mode: MeshColliderMode::ConvexDecomposition(VHACDParameters::default()),
+        ..default()
+    };
 
     let scene = SceneRoot(assets.load(
         GltfAssetLabel::Scene(0).from_asset("./concave.glb")
@@ -132,7 +128,7 @@ fn spawn_concave_obj(
 
     commands.spawn((
         scene,
-        mesh_collider,
+        scene_collider,
         Transform::from_xyz(0., 7., 0.),
         Velocity(Vec3::ZERO),
         Mass(1.),
@@ -150,19 +146,332 @@ fn spawn_test_plat(
     mut commands: Commands,
 ) {
 
-    let collider_shape = assets.load("./test_plat.glb#Mesh0/Primitive0"); 
-
-    let mesh_collider = MeshCollider(collider_shape);
-
     let scene = SceneRoot(assets.load(
         GltfAssetLabel::Scene(0).from_asset("./test_plat.glb")
     ));
 
     commands.spawn((
         scene,
-        mesh_collider,
+        SceneCollider::default(),
         Transform::from_xyz(0., 10., 0.),
         RigidBody::Static,
         Ground
     ));
-}
\ No newline at end of file
+}
+
+
+// A chair made of several boxes, each child collider is merged into the body
+fn spawn_chair(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>, 
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    let material = materials.add(Color::linear_rgb(0.5, 0.3, 0.1));
+
+    let seat = Cuboid::new(1., 0.1, 1.);
+    let leg = Cuboid::new(0.1, 0.5, 0.1);
+    let back = Cuboid::new(1., 1., 0.1);
+
+    let parts = [
+        (seat, Vec3::new(0., 0., 0.)),
+        (leg, Vec3::new(-0.45, -0.3, -0.45)),
+        (leg, Vec3::new(0.45, -0.3, -0.45)),
+        (leg, Vec3::new(-0.45, -0.3, 0.45)),
+        (leg, Vec3::new(0.45, -0.3, 0.45)),
+        (back, Vec3::new(0., 0.55, 0.45)),
+    ];
+
+    commands.spawn((
+        Transform::from_xyz(4., 8., 0.),
+        Visibility::default(),
+        RigidBody::Dynamic,
+        Velocity(Vec3::ZERO),
+        Mass(2.),
+    )).with_children(|chair| {
+        for (shape, position) in parts {
+            chair.spawn((
+                Mesh3d(meshes.add(shape)),
+                MeshMaterial3d(material.clone()),
+                Transform::from_translation(position),
+                Collider::from(shape),
+            ));
+        }
+    });
+}
+
+
+// A chain of links hanging from a static anchor, each link connected to the previous one by a ball joint
+fn spawn_chain(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>, 
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    let links = 6;
+    let link = Capsule3d::new(0.1, 0.4);
+    let link_length = 0.6;
+
+    let mesh = meshes.add(link);
+    let material = materials.add(Color::linear_rgb(0.6, 0.6, 0.6));
+
+    let mut previous = commands.spawn((
+        Transform::from_xyz(-5., 12., 0.),
+        RigidBody::Static,
+        Collider::ball(0.1),
+    )).id();
+
+    for i in 0..links {
+        let link_entity = commands.spawn((
+            Mesh3d(mesh.clone()),
+            MeshMaterial3d(material.clone()),
+            Transform::from_xyz(-5., 12. - link_length * (i as f32 + 0.5), 0.),
+            RigidBody::Dynamic,
+            Velocity(Vec3::ZERO),
+            AngularVelocity::default(),
+            Collider::from(link),
+        )).id();
+
+        // the first link hangs from the anchor's center
+        let previous_anchor = if i == 0 { Vec3::ZERO } else { Vec3::new(0., -link_length / 2., 0.) };
+
+        commands.spawn(
+            Joint::spherical(previous, link_entity)
+                .with_anchors(previous_anchor, Vec3::new(0., link_length / 2., 0.))
+        );
+
+        previous = link_entity;
+    }
+}
+
+
+// A cube bouncing on a spring hanging from a world point
+fn spawn_spring(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>, 
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    let cube = commands.spawn((
+        Mesh3d(meshes.add(Cuboid::new(0.5, 0.5, 0.5))),
+        MeshMaterial3d(materials.add(Color::linear_rgb(0., 0.6, 1.))),
+        Transform::from_xyz(5., 10., 0.),
+        RigidBody::Dynamic,
+        Velocity(Vec3::ZERO),
+        Collider::cuboid(Vec3::splat(0.25)),
+    )).id();
+
+    commands.spawn(Spring::to_world(cube, Vec3::new(5., 12., 0.), 1.5, 20., 0.5));
+}
+
+
+// A pool of water with a light crate floating in it and a heavy one sinking
+fn spawn_pool(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>, 
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    let water = Cuboid::new(6., 2., 6.);
+
+    commands.spawn((
+        Mesh3d(meshes.add(water)),
+        MeshMaterial3d(materials.add(StandardMaterial {
+            base_color: Color::linear_rgba(0., 0.3, 0.8, 0.4),
+            alpha_mode: AlphaMode::Blend,
+            ..default()
+        })),
+        Transform::from_xyz(-10., 11., -10.),
+        RigidBody::Static,
+        Collider::from(water),
+        FluidVolume::default(),
+    ));
+
+    let crate_shape = Cuboid::new(1., 1., 1.);
+
+    for (x, mass) in [(-11., 0.5), (-9., 3.)] {
+        commands.spawn((
+            Mesh3d(meshes.add(crate_shape)),
+            MeshMaterial3d(materials.add(Color::linear_rgb(0.6, 0.4, 0.2))),
+            Transform::from_xyz(x, 14., -10.),
+            RigidBody::Dynamic,
+            Velocity(Vec3::ZERO),
+            AngularVelocity::default(),
+            Mass(mass),
+            Collider::from(crate_shape),
+        ));
+    }
+}
+
+
+// A vortex spinning a few balls around it, pulling them towards its center as they get closer
+fn spawn_vortex(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>, 
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    let center = Vec3::new(10., 12., -10.);
+    let field = Sphere::new(4.);
+
+    commands.spawn((
+        Transform::from_translation(center),
+        RigidBody::Static,
+        Collider::from(field),
+        ForceField::new(ForceFieldKind::Vortex { axis: Vec3::Y }, 6.),
+    ));
+
+    commands.spawn((
+        Transform::from_translation(center),
+        RigidBody::Static,
+        Collider::from(field),
+        ForceField::new(ForceFieldKind::Attractor, 3.).with_falloff(Falloff::Linear { distance: 4. }),
+    ));
+
+    let ball = Sphere::new(0.25);
+
+    for x in [-2., 2.] {
+        commands.spawn((
+            Mesh3d(meshes.add(ball)),
+            MeshMaterial3d(materials.add(Color::linear_rgb(0.8, 0.2, 0.6))),
+            Transform::from_translation(center + Vec3::new(x, 1., 0.)),
+            RigidBody::Dynamic,
+            Velocity(Vec3::ZERO),
+            Collider::from(ball),
+        ));
+    }
+}
+
+
+// A platform a ball is thrown through from below, landing on top of it
+fn spawn_one_way_platform(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>, 
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    let platform = Cuboid::new(3., 0.2, 3.);
+
+    commands.spawn((
+        Mesh3d(meshes.add(platform)),
+        MeshMaterial3d(materials.add(Color::linear_rgb(0.3, 0.7, 0.3))),
+        Transform::from_xyz(0., 14., 10.),
+        RigidBody::Static,
+        Collider::from(platform),
+        OneWayPlatform::default(),
+    ));
+
+    let ball = Sphere::new(0.25);
+
+    commands.spawn((
+        Mesh3d(meshes.add(ball)),
+        MeshMaterial3d(materials.add(Color::linear_rgb(0.9, 0.8, 0.1))),
+        Transform::from_xyz(0., 12., 10.),
+        RigidBody::Dynamic,
+        Velocity(Vec3::Y * 8.),
+        Collider::from(ball),
+    ));
+}
+
+
+// A four wheeled car, driven with the arrow keys and braking with space
+fn spawn_vehicle(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>, 
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    let chassis = Cuboid::new(2., 0.5, 4.);
+
+    let wheels = vec![
+        Wheel::new(Vec3::new(-0.9, -0.25, -1.5), 0.4).steered(),
+        Wheel::new(Vec3::new(0.9, -0.25, -1.5), 0.4).steered(),
+        Wheel::new(Vec3::new(-0.9, -0.25, 1.5), 0.4).driven(),
+        Wheel::new(Vec3::new(0.9, -0.25, 1.5), 0.4).driven(),
+    ];
+
+    commands.spawn((
+        Mesh3d(meshes.add(chassis)),
+        MeshMaterial3d(materials.add(Color::linear_rgb(0.8, 0.1, 0.1))),
+        Transform::from_xyz(-5., 12., 5.),
+        RigidBody::Dynamic,
+        Velocity(Vec3::ZERO),
+        AngularVelocity::default(),
+        Collider::from(chassis),
+        Vehicle::new(wheels),
+    ));
+}
+
+
+// Sets the inputs of the vehicles from the keyboard
+fn drive_vehicles(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut vehicle_q: Query<&mut Vehicle>,
+) {
+    let axis = |positive: KeyCode, negative: KeyCode| {
+        keys.pressed(positive) as i32 as f32 - keys.pressed(negative) as i32 as f32
+    };
+
+    for mut vehicle in vehicle_q.iter_mut() {
+        vehicle.throttle = axis(KeyCode::ArrowUp, KeyCode::ArrowDown);
+        vehicle.steering = axis(KeyCode::ArrowLeft, KeyCode::ArrowRight);
+        vehicle.brake = keys.pressed(KeyCode::Space) as i32 as f32;
+    }
+}
+
+
+// A small planet with its own gravity and a character standing on it
+fn spawn_planet(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>, 
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    let center = Vec3::new(15., 15., 10.);
+    let planet = Sphere::new(3.);
+
+    commands.spawn((
+        Mesh3d(meshes.add(planet)),
+        MeshMaterial3d(materials.add(Color::linear_rgb(0.2, 0.5, 0.9))),
+        Transform::from_translation(center),
+        RigidBody::Static,
+        Collider::from(planet),
+    ));
+
+    // the planet's gravity reaches a few meters above its surface
+    commands.spawn((
+        Transform::from_translation(center),
+        RigidBody::Static,
+        Collider::from(Sphere::new(8.)),
+        ForceField::new(ForceFieldKind::Attractor, GRAVITY).with_falloff(Falloff::InverseSquare { distance: 3. }),
+    ));
+
+    let character = Capsule3d::new(0.3, 1.);
+
+    commands.spawn((
+        Mesh3d(meshes.add(character)),
+        MeshMaterial3d(materials.add(Color::linear_rgb(0.9, 0.9, 0.9))),
+        Transform::from_translation(center + Vec3::Y * 4.),
+        RigidBody::Kinematic,
+        Velocity(Vec3::ZERO),
+        Collider::from(character),
+        // only the planet pulls the character
+        CharacterController {
+            gravity: 0.,
+            ..default()
+        },
+    ));
+}
+
+
+// Walks the characters with WASD, relative to the way they face and along their ground
+fn walk_characters(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut character_q: Query<(&GlobalTransform, &mut Velocity), With<CharacterController>>,
+) {
+    let axis = |positive: KeyCode, negative: KeyCode| {
+        keys.pressed(positive) as i32 as f32 - keys.pressed(negative) as i32 as f32
+    };
+
+    let input = Vec3::new(axis(KeyCode::KeyD, KeyCode::KeyA), 0., axis(KeyCode::KeyS, KeyCode::KeyW));
+
+    for (global_transform, mut velocity) in character_q.iter_mut() {
+        let (_, rotation, _) = global_transform.to_scale_rotation_translation();
+        let up = rotation * Vec3::Y;
+
+        // the speed along up is left to gravity
+        velocity.0 = up * velocity.0.dot(up) + rotation * input * 3.;
+    }
+}
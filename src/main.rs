@@ -2,7 +2,7 @@ use bevy::{diagnostic::{DiagnosticsStore, FrameTimeDiagnosticsPlugin}, prelude::
 
 #[path="./physics/physics.rs"]
 mod physics;
-use physics::PhysicsPlugin;
+use physics::{debug::PhysicsDebugPlugin, PhysicsPlugin};
 
 #[path="./game/camera/camera.rs"]
 mod camera;
@@ -20,6 +20,7 @@ fn main() {
             CameraPlugin,
             LevelPlugin,
             PhysicsPlugin,
+            PhysicsDebugPlugin,
             
             FrameTimeDiagnosticsPlugin::default()
 
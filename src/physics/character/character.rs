@@ -0,0 +1,420 @@
+use bevy::prelude::*;
+use nalgebra::{Isometry3, Vector3};
+use parry3d::query::{self, ShapeCastOptions};
+
+use super::collisions::collider::{CollisionLayers, OneWayPlatform, ScaledShape, Sensor};
+use super::collisions::passes_through;
+use super::collisions::octree::Octree;
+use super::collisions::utils::{global_transform_to_isometry, rotate_global_transform, translate_global_transform, transform_to_isometry};
+use super::mass::MassProperties;
+use super::{RigidBody, Velocity, DELTA, GRAVITY};
+
+// the number of times a movement can slide along the surfaces it hits
+const MAX_SLIDES: usize = 4;
+
+// the movements shorter than this are skipped
+const MIN_MOVEMENT: f32 = 1e-4;
+
+// the smallest change of the up direction, in radians, for which the character is turned upright
+const MIN_UP_ANGLE: f32 = 1e-4;
+
+
+/// Moves a RigidBody::Kinematic body with its Velocity by sliding its collider along the surfaces it hits.
+/// The Velocity is set by the game and updated with the movement that actually happened.
+/// The character's up is opposite to its gravity, and the character is turned to stand upright,
+/// so with an attractor ForceField it can walk around a small planet.
+/// Characters don't block each other and pass through sensors
+#[derive(Component, Clone)]
+pub struct CharacterController {
+    /// The steepest slope the character can walk on, in radians
+    pub max_slope_angle: f32,
+    /// The highest step the character can walk onto
+    pub step_height: f32,
+    /// How far below the character the ground is searched to keep it on the ground when walking down slopes and steps
+    pub snap_distance: f32,
+    /// The gap kept between the character and the surfaces, so it doesn't get stuck in them
+    pub skin_width: f32,
+    /// The acceleration pulling the character down along -Y when it isn't grounded
+    pub gravity: f32,
+    /// The acceleration of the force fields the character is in, added to its gravity.
+    /// Updated each tick
+    pub field_acceleration: Vec3,
+    /// If set, the dynamic bodies hit by the character are pushed with this fraction of its momentum,
+    /// so heavier bodies move less
+    pub push_strength: Option<f32>,
+    /// The one way platforms the character is passing through, updated each tick.
+    /// They are ignored until the character stops overlapping them, so it isn't pushed back halfway through
+    pub passing_platforms: Vec<Entity>,
+}
+
+impl Default for CharacterController {
+    fn default() -> Self {
+        CharacterController {
+            max_slope_angle: 45_f32.to_radians(),
+            step_height: 0.3,
+            snap_distance: 0.2,
+            skin_width: 0.02,
+            gravity: GRAVITY,
+            field_acceleration: Vec3::ZERO,
+            push_strength: Some(1.),
+            passing_platforms: Vec::new(),
+        }
+    }
+}
+
+/// Added to the characters standing on walkable ground
+#[derive(Component, Clone, Copy)]
+pub struct Grounded {
+    /// The normal of the ground surface
+    pub normal: Vec3,
+    /// The body the character stands on
+    pub entity: Entity,
+}
+
+
+// A body the character can hit
+struct Obstacle<'a> {
+    entity: Entity,
+    isometry: Isometry3<f32>,
+    shape: &'a ScaledShape,
+    /// The world pass through direction, if the body is a one way platform
+    one_way: Option<Vec3>,
+}
+
+// The first obstacle hit by a movement
+#[derive(Clone, Copy)]
+struct CastHit {
+    entity: Entity,
+    /// The fraction of the movement done before the hit
+    time_of_impact: f32,
+    /// The normal of the surface hit, pointing towards the character
+    normal: Vec3,
+}
+
+// The character's shape and the obstacles around it
+struct CharacterCast<'a> {
+    shape: &'a ScaledShape,
+    rotation: Quat,
+    /// The direction opposite to the character's gravity
+    up: Vec3,
+    skin_width: f32,
+    obstacles: Vec<Obstacle<'a>>,
+}
+
+impl CharacterCast<'_> {
+    // Casts the character's shape from the position along the movement and returns the first hit
+    fn cast(&self, position: Vec3, movement: Vec3) -> Option<CastHit> {
+        let isometry = transform_to_isometry(Transform::from_translation(position).with_rotation(self.rotation));
+        let velocity = Vector3::new(movement.x, movement.y, movement.z);
+
+        let options = ShapeCastOptions {
+            max_time_of_impact: 1.,
+            target_distance: self.skin_width,
+            stop_at_penetration: false,
+            compute_impact_geometry_on_penetration: true,
+        };
+
+        self.obstacles.iter()
+            .filter_map(|obstacle| {
+                let hit = query::cast_shapes(
+                    &isometry,
+                    &velocity,
+                    &*self.shape.shape,
+                    &obstacle.isometry,
+                    &Vector3::zeros(),
+                    &*obstacle.shape.shape,
+                    options
+                ).ok()??;
+
+                let normal = obstacle.isometry.rotation * hit.normal2;
+                let normal = Vec3::new(normal.x, normal.y, normal.z);
+
+                // a one way platform doesn't block the movements coming from its pass through side
+                if obstacle.one_way.is_some_and(|direction| passes_through(direction, normal, movement)) {
+                    return None;
+                }
+
+                Some(CastHit {
+                    entity: obstacle.entity,
+                    time_of_impact: hit.time_of_impact,
+                    normal,
+                })
+            })
+            .min_by(|hit_1, hit_2| hit_1.time_of_impact.total_cmp(&hit_2.time_of_impact))
+    }
+
+    // Moves from the position, sliding along the surfaces hit.
+    // Returns the final position and the surfaces hit.
+    // The surfaces steeper than the max slope are treated as vertical walls, so they can't be climbed
+    fn move_and_slide(&self, position: Vec3, movement: Vec3, max_slope_angle: f32, hits: &mut Vec<CastHit>) -> Vec3 {
+        let mut position = position;
+        let mut remaining = movement;
+
+        for _ in 0..MAX_SLIDES {
+            if remaining.length() < MIN_MOVEMENT {
+                break;
+            }
+
+            let Some(hit) = self.cast(position, remaining) else {
+                position += remaining;
+                break;
+            };
+
+            position += remaining * hit.time_of_impact;
+            remaining *= 1. - hit.time_of_impact;
+
+            hits.push(hit);
+
+            let normal = match is_walkable(hit.normal, self.up, max_slope_angle) {
+                true => hit.normal,
+                false => hit.normal.reject_from_normalized(self.up).normalize_or(hit.normal),
+            };
+
+            // only the part of the movement going into the surface is removed
+            remaining -= normal * remaining.dot(normal).min(0.);
+        }
+
+        position
+    }
+
+    // The walkable ground within the distance below the position
+    fn ground(&self, position: Vec3, distance: f32, max_slope_angle: f32) -> Option<(CastHit, Vec3)> {
+        let movement = -self.up * distance;
+        let hit = self.cast(position, movement)?;
+
+        is_walkable(hit.normal, self.up, max_slope_angle).then_some((hit, position + movement * hit.time_of_impact))
+    }
+}
+
+
+// If a surface with the normal isn't too steep to walk on for a character standing along up
+fn is_walkable(normal: Vec3, up: Vec3, max_slope_angle: f32) -> bool {
+    normal.angle_between(up) <= max_slope_angle
+}
+
+
+/// Moves the characters with their Velocity, sliding them along the surfaces they hit,
+/// climbing steps and snapping them to the ground
+pub fn move_characters(
+    mut character_q: Query<(
+        Entity,
+        &mut CharacterController,
+        &mut Velocity,
+        &mut GlobalTransform,
+        &ScaledShape,
+        Option<&Grounded>,
+        Option<&MassProperties>,
+        Option<&CollisionLayers>
+    )>,
+    body_q: Query<(
+        &GlobalTransform,
+        &ScaledShape,
+        &RigidBody,
+        Option<&MassProperties>,
+        Option<&CollisionLayers>,
+        Option<&OneWayPlatform>
+    ), (Without<CharacterController>, Without<Sensor>)>,
+    mut push_q: Query<&mut Velocity, Without<CharacterController>>,
+    octree: Res<Octree>,
+    mut commands: Commands,
+) {
+    for (entity, mut controller, mut velocity, mut global_transform, shape, grounded, mass_properties, layers) in character_q.iter_mut() {
+        let (_, rotation, start) = global_transform.to_scale_rotation_translation();
+        let layers = layers.copied().unwrap_or_default();
+
+        let gravity = Vec3::NEG_Y * controller.gravity + controller.field_acceleration;
+        let up = (-gravity).normalize_or(Vec3::Y);
+
+        // turns the character to stand upright along its up
+        let upright = Quat::from_rotation_arc(rotation * Vec3::Y, up);
+        let rotation = match Quat::IDENTITY.angle_between(upright) > MIN_UP_ANGLE {
+            true => upright * rotation,
+            false => rotation,
+        };
+
+        // gravity only pulls the characters that are in the air
+        match grounded {
+            Some(_) => {
+                let v = velocity.0;
+                velocity.0 = v - up * v.dot(up).min(0.);
+            }
+            None => velocity.0 += gravity * DELTA,
+        }
+
+        let movement = velocity.0 * DELTA;
+        let vertical = up * movement.dot(up);
+        let horizontal = movement - vertical;
+
+        // the obstacles the character can reach this tick
+        let bounding_sphere = shape.shape.compute_local_bounding_sphere();
+        let bounding_radius = bounding_sphere.center().coords.norm() + bounding_sphere.radius();
+        let reach = bounding_radius + movement.length() + controller.step_height + controller.snap_distance + controller.skin_width;
+
+        let (passing, obstacles): (Vec<Obstacle>, Vec<Obstacle>) = octree.entities_within_radius(start, reach).into_iter()
+            .filter_map(|obstacle| {
+                let (global_transform, shape, _, _, obstacle_layers, one_way) = body_q.get(obstacle).ok()?;
+
+                if !layers.interacts_with(&obstacle_layers.copied().unwrap_or_default()) {
+                    return None;
+                }
+
+                let (_, obstacle_rotation, _) = global_transform.to_scale_rotation_translation();
+
+                Some(Obstacle {
+                    entity: obstacle,
+                    isometry: global_transform_to_isometry(global_transform),
+                    shape,
+                    one_way: one_way.map(|one_way| (obstacle_rotation * one_way.direction).normalize_or_zero()),
+                })
+            })
+            // the platforms the character is passing through don't block it
+            .partition(|obstacle| controller.passing_platforms.contains(&obstacle.entity));
+
+        let cast = CharacterCast {
+            shape,
+            rotation,
+            up,
+            skin_width: controller.skin_width,
+            obstacles,
+        };
+
+        let mut hits = Vec::new();
+        let mut position = cast.move_and_slide(start, horizontal, controller.max_slope_angle, &mut hits);
+
+        // if blocked by a wall while on the ground, tries to climb it as a step
+        let blocked = hits.iter().any(|hit| !is_walkable(hit.normal, up, controller.max_slope_angle));
+
+        if blocked && grounded.is_some() && controller.step_height > 0. {
+            if let Some(stepped) = step_up(&cast, &controller, start, horizontal) {
+                // only if the step moves the character further than sliding
+                if horizontal_distance(start, stepped, up) > horizontal_distance(start, position, up) + MIN_MOVEMENT {
+                    position = stepped;
+                    hits.retain(|hit| is_walkable(hit.normal, up, controller.max_slope_angle));
+                }
+            }
+        }
+
+        position = cast.move_and_slide(position, vertical, controller.max_slope_angle, &mut hits);
+
+        // keeps the character on the ground when walking down slopes and steps
+        if grounded.is_some() && velocity.0.dot(up) <= 0. {
+            if let Some((_, snapped)) = cast.ground(position, controller.snap_distance, controller.max_slope_angle) {
+                position = snapped;
+            }
+        }
+
+        // removes the velocity going into the surfaces hit, so the character doesn't keep pushing into them
+        for hit in hits.iter() {
+            let v = velocity.0;
+            velocity.0 = v - hit.normal * v.dot(hit.normal).min(0.);
+        }
+
+        if let Some(push_strength) = controller.push_strength {
+            let character_mass = mass_properties.map_or(1., |mass_properties| mass_properties.mass);
+
+            for hit in hits.iter() {
+                push_body(hit, movement / DELTA, character_mass, push_strength, &body_q, &mut push_q);
+            }
+        }
+
+        match cast.ground(position, controller.skin_width * 2., controller.max_slope_angle) {
+            Some((hit, _)) => {
+                commands.entity(entity).insert(Grounded {
+                    normal: hit.normal,
+                    entity: hit.entity,
+                });
+            }
+            None => {
+                if grounded.is_some() {
+                    commands.entity(entity).remove::<Grounded>();
+                }
+            }
+        }
+
+        // the one way platforms the character still overlaps after passing into them
+        let character_isometry = transform_to_isometry(Transform::from_translation(position).with_rotation(rotation));
+
+        let passing_platforms = passing.iter()
+            .chain(cast.obstacles.iter().filter(|obstacle| {
+                obstacle.one_way.is_some_and(|direction| movement.dot(direction) > 0.)
+            }))
+            .filter(|obstacle| {
+                query::intersection_test(&character_isometry, &*shape.shape, &obstacle.isometry, &*obstacle.shape.shape)
+                    .unwrap_or(false)
+            })
+            .map(|obstacle| obstacle.entity)
+            .collect();
+
+        controller.passing_platforms = passing_platforms;
+
+        if Quat::IDENTITY.angle_between(upright) > MIN_UP_ANGLE {
+            rotate_global_transform(&mut global_transform, upright, start);
+        }
+
+        if position != start {
+            translate_global_transform(&mut global_transform, position - start);
+        }
+    }
+}
+
+
+// Moves the character up by the step height, forwards and back down.
+// Returns where it lands, if it lands on walkable ground
+fn step_up(cast: &CharacterCast, controller: &CharacterController, start: Vec3, horizontal: Vec3) -> Option<Vec3> {
+    let up = cast.up * controller.step_height;
+    let raised = match cast.cast(start, up) {
+        Some(hit) => start + up * hit.time_of_impact,
+        None => start + up,
+    };
+
+    let forward = cast.move_and_slide(raised, horizontal, controller.max_slope_angle, &mut Vec::new());
+
+    let (_, landed) = cast.ground(forward, (raised - start).dot(cast.up) + controller.skin_width, controller.max_slope_angle)?;
+
+    Some(landed)
+}
+
+
+// The distance between the points across the up direction
+fn horizontal_distance(from: Vec3, to: Vec3, up: Vec3) -> f32 {
+    (to - from).reject_from_normalized(up).length()
+}
+
+
+// Pushes a dynamic body hit by the character, heavier bodies are pushed less
+fn push_body(
+    hit: &CastHit,
+    character_velocity: Vec3,
+    character_mass: f32,
+    push_strength: f32,
+    body_q: &Query<(
+        &GlobalTransform,
+        &ScaledShape,
+        &RigidBody,
+        Option<&MassProperties>,
+        Option<&CollisionLayers>,
+        Option<&OneWayPlatform>
+    ), (Without<CharacterController>, Without<Sensor>)>,
+    push_q: &mut Query<&mut Velocity, Without<CharacterController>>,
+) {
+    let Ok((_, _, RigidBody::Dynamic, body_mass, ..)) = body_q.get(hit.entity) else {
+        return;
+    };
+
+    let Ok(mut body_velocity) = push_q.get_mut(hit.entity) else {
+        return;
+    };
+
+    // the speed of the character into the body, relative to it
+    let direction = -hit.normal;
+    let speed = (character_velocity - body_velocity.0).dot(direction);
+
+    if speed <= 0. {
+        return;
+    }
+
+    let body_mass = body_mass.map_or(1., |body_mass| body_mass.mass);
+    let share = character_mass / (character_mass + body_mass);
+
+    body_velocity.0 += direction * speed * share * push_strength;
+}
@@ -1,7 +1,17 @@
+use std::collections::HashMap;
+
 use bevy::prelude::*;
-use parry3d::shape::*;
+use bevy::scene::{SceneInstance, SceneSpawner};
+use nalgebra::{DMatrix, Isometry3, Point3, Vector3};
+use parry3d::bounding_volume::Aabb;
+use parry3d::shape::SharedShape;
+
+pub use parry3d::transformation::vhacd::VHACDParameters;
 
-use super::extract_mesh_vertices_indices;
+use super::fitting::{fit_aabb, fit_bounding_sphere, fit_capsule, fit_obb};
+use super::heightfield::assign_heightfield_collider;
+use super::scaling::scale_shape;
+use super::{extract_mesh_vertices_indices, transform_to_isometry, ColliderGenerationError, RigidBody, VerticesIndices};
 
 
 pub struct ColliderPlugin;
@@ -9,46 +19,436 @@ pub struct ColliderPlugin;
 impl Plugin for ColliderPlugin {
     fn build(&self, app: &mut App) {
         app
-            .add_systems(Update, assign_mesh_collider);
+            .add_event::<ColliderGenerationFailed>()
+            .add_systems(Update, (
+                reload_mesh_colliders,
+                assign_mesh_collider,
+                assign_scene_collider,
+                assign_heightfield_collider,
+                merge_child_colliders,
+            ).chain());
     }
 }
 
 
-/// A collider
-#[derive(Component)]
+/// A collider.
+/// The shape is reference counted, so cloning a collider shares its geometry.
+/// Colliders on the children of a RigidBody are merged into the body's Collider
+/// as a compound, together with the body's own Collider. Trimesh and heightfield children can't be merged and are skipped
+#[derive(Component, Clone)]
+#[require(ColliderAabb)]
 pub struct Collider {
-    pub shape: Box<dyn Shape>,
+    pub shape: SharedShape,
+}
+
+/// The Collider a RigidBody had before the colliders of its children were merged into it.
+/// It's part of the merged compound, and restored when the body has no child colliders left.
+/// Change this instead of the Collider to change the body's own shape once children are merged
+#[derive(Component, Clone)]
+pub struct OwnCollider(pub Option<Collider>);
+
+/// The shape of a collider scaled by its entity's Transform scale.
+/// Rebuilt only when the scale or the Collider change
+#[derive(Component, Clone)]
+pub struct ScaledShape {
+    pub shape: SharedShape,
+    pub scale: Vec3,
+}
+
+impl ScaledShape {
+    /// Scales the shape, keeping it unscaled if parry can't scale it
+    pub fn new(shape: &SharedShape, scale: Vec3) -> Self {
+        ScaledShape {
+            shape: scale_shape(shape, scale).unwrap_or_else(|| shape.clone()),
+            scale,
+        }
+    }
+}
+
+/// Makes a collider only detect the bodies overlapping it, without generating contacts.
+/// Used by volumes like fluids and force fields
+#[derive(Component, Clone, Copy, Default)]
+pub struct Sensor;
+
+/// Makes a collider solid only from one side, like a platform that can be jumped through from below.
+/// The bodies moving along the direction, in the collider's local space, pass through it.
+/// A body that started passing through keeps passing until it stops touching the collider
+#[derive(Component, Clone, Copy)]
+pub struct OneWayPlatform {
+    pub direction: Vec3,
+}
+
+impl Default for OneWayPlatform {
+    fn default() -> Self {
+        OneWayPlatform { direction: Vec3::Y }
+    }
+}
+
+/// The layers a body belongs to and the layers it interacts with, as bit masks.
+/// Two bodies only collide if each belongs to a layer the other interacts with.
+/// The bodies without it belong to and interact with every layer
+#[derive(Component, Clone, Copy)]
+pub struct CollisionLayers {
+    pub memberships: u32,
+    pub filters: u32,
+}
+
+impl Default for CollisionLayers {
+    fn default() -> Self {
+        CollisionLayers {
+            memberships: u32::MAX,
+            filters: u32::MAX,
+        }
+    }
+}
+
+impl CollisionLayers {
+    pub fn new(memberships: u32, filters: u32) -> Self {
+        CollisionLayers { memberships, filters }
+    }
+
+    pub fn interacts_with(&self, other: &CollisionLayers) -> bool {
+        self.memberships & other.filters != 0 && other.memberships & self.filters != 0
+    }
+}
+
+/// The world space aabb of a collider.
+/// Recomputed only when the collider's transform or shape changes
+#[derive(Component, Clone, Copy)]
+pub struct ColliderAabb(pub Aabb);
+
+impl Default for ColliderAabb {
+    fn default() -> Self {
+        ColliderAabb(Aabb::new_invalid())
+    }
 }
 
-/// A collider generated from a mesh
+impl Collider {
+    /// A box with the given half extents
+    pub fn cuboid(half_extents: Vec3) -> Self {
+        Collider {
+            shape: SharedShape::cuboid(half_extents.x, half_extents.y, half_extents.z),
+        }
+    }
+
+    /// A sphere with the given radius
+    pub fn ball(radius: f32) -> Self {
+        Collider {
+            shape: SharedShape::ball(radius),
+        }
+    }
+
+    /// A capsule aligned with the Y axis
+    pub fn capsule(half_height: f32, radius: f32) -> Self {
+        Collider {
+            shape: SharedShape::capsule_y(half_height, radius),
+        }
+    }
+
+    /// A cylinder aligned with the Y axis
+    pub fn cylinder(half_height: f32, radius: f32) -> Self {
+        Collider {
+            shape: SharedShape::cylinder(half_height, radius),
+        }
+    }
+
+    /// A cone aligned with the Y axis, with its apex pointing up
+    pub fn cone(half_height: f32, radius: f32) -> Self {
+        Collider {
+            shape: SharedShape::cone(half_height, radius),
+        }
+    }
+
+    /// The convex hull of a set of points.
+    /// Returns None if the hull can't be computed, e.g. if the points are coplanar
+    pub fn convex_hull(points: &[Vec3]) -> Option<Self> {
+        let points: Vec<_> = points.iter().map(|point| vec3_to_point(*point)).collect();
+
+        Some(Collider {
+            shape: SharedShape::convex_hull(&points)?,
+        })
+    }
+
+    /// A triangle mesh.
+    /// Returns an error if there are no triangles or an index is out of the vertices
+    pub fn trimesh(vertices: &[Vec3], indices: Vec<[u32; 3]>) -> Result<Self, ColliderGenerationError> {
+        validate_indices(vertices, &indices)?;
+
+        Ok(Collider {
+            shape: SharedShape::trimesh(
+                vertices.iter().map(|vertex| vec3_to_point(*vertex)).collect(), 
+                indices
+            ),
+        })
+    }
+
+    /// A triangle mesh matching a Bevy mesh.
+    /// Returns an error if the mesh's vertices can't be read or it has no valid triangles
+    pub fn from_mesh(mesh: &Mesh) -> Result<Self, ColliderGenerationError> {
+        Ok(Collider {
+            shape: build_mesh_shape(mesh, &MeshColliderMode::Trimesh)?,
+        })
+    }
+
+    /// A compound of convex hulls approximating a concave triangle mesh.
+    /// Returns an error if there are no triangles or an index is out of the vertices
+    pub fn convex_decomposition(vertices: &[Vec3], indices: &[[u32; 3]], params: &VHACDParameters) -> Result<Self, ColliderGenerationError> {
+        validate_indices(vertices, indices)?;
+
+        let vertices: Vec<_> = vertices.iter().map(|vertex| vec3_to_point(*vertex)).collect();
+
+        Ok(Collider {
+            shape: SharedShape::convex_decomposition_with_params(&vertices, indices, params),
+        })
+    }
+
+    /// A heightfield centered on the origin.
+    /// heights[row][column] is the height of a point, rows go along Z and columns along X.
+    /// The heightfield is scaled so that it is scale.x wide, scale.z deep, and heights are multiplied by scale.y.
+    /// Returns an error if the rows don't all have the same length or there are fewer than 2 rows or columns
+    pub fn heightfield(heights: &[Vec<f32>], scale: Vec3) -> Result<Self, ColliderGenerationError> {
+        let rows = heights.len();
+        let columns = heights.first().map_or(0, |row| row.len());
+
+        if rows < 2 || columns < 2 || heights.iter().any(|row| row.len() != columns) {
+            return Err(ColliderGenerationError::InvalidHeights);
+        }
+
+        Ok(Collider {
+            shape: SharedShape::heightfield(
+                DMatrix::from_fn(rows, columns, |row, column| heights[row][column]), 
+                Vector3::new(scale.x, scale.y, scale.z)
+            ),
+        })
+    }
+
+    /// A compound of colliders, each placed at a position and rotation relative to the body.
+    /// Compound colliders are flattened.
+    /// Returns an error if there are no colliders or one of them is a trimesh or a heightfield
+    pub fn compound(colliders: Vec<(Vec3, Quat, Collider)>) -> Result<Self, ColliderGenerationError> {
+        let shapes = colliders.into_iter()
+            .map(|(position, rotation, collider)| (
+                transform_to_isometry(Transform::from_translation(position).with_rotation(rotation)),
+                collider.shape
+            ))
+            .collect();
+
+        Ok(Collider {
+            shape: compound_shape(shapes)?,
+        })
+    }
+}
+
+impl From<Cuboid> for Collider {
+    fn from(cuboid: Cuboid) -> Self {
+        Collider::cuboid(cuboid.half_size)
+    }
+}
+
+impl From<Sphere> for Collider {
+    fn from(sphere: Sphere) -> Self {
+        Collider::ball(sphere.radius)
+    }
+}
+
+impl From<Capsule3d> for Collider {
+    fn from(capsule: Capsule3d) -> Self {
+        Collider::capsule(capsule.half_length, capsule.radius)
+    }
+}
+
+impl From<Cylinder> for Collider {
+    fn from(cylinder: Cylinder) -> Self {
+        Collider::cylinder(cylinder.half_height, cylinder.radius)
+    }
+}
+
+impl From<Cone> for Collider {
+    fn from(cone: Cone) -> Self {
+        Collider::cone(cone.height / 2., cone.radius)
+    }
+}
+
+fn vec3_to_point(vec: Vec3) -> Point3<f32> {
+    Point3::new(vec.x, vec.y, vec.z)
+}
+
+// Checks that there is at least a triangle and every index points to a vertex,
+// since parry panics otherwise
+fn validate_indices(vertices: &[Vec3], indices: &[[u32; 3]]) -> Result<(), ColliderGenerationError> {
+    if indices.is_empty() {
+        return Err(ColliderGenerationError::NoTriangles);
+    }
+
+    if indices.iter().flatten().any(|index| *index as usize >= vertices.len()) {
+        return Err(ColliderGenerationError::InvalidIndices);
+    }
+
+    Ok(())
+}
+
+// Builds a compound out of the shapes, moving the parts of nested compounds into it
+// since parry doesn't allow nesting them.
+// The other composite shapes, trimeshes and heightfields, can't be part of a compound
+fn compound_shape(shapes: Vec<(Isometry3<f32>, SharedShape)>) -> Result<SharedShape, ColliderGenerationError> {
+    let mut parts = Vec::new();
+
+    for (isometry, shape) in shapes {
+        match shape.as_compound() {
+            Some(compound) => {
+                for (sub_isometry, sub_shape) in compound.shapes() {
+                    parts.push((isometry * sub_isometry, sub_shape.clone()));
+                }
+            }
+            None if shape.as_composite_shape().is_some() || shape.as_heightfield().is_some() => {
+                return Err(ColliderGenerationError::NestedComposite);
+            }
+            None => parts.push((isometry, shape)),
+        }
+    }
+
+    if parts.is_empty() {
+        return Err(ColliderGenerationError::EmptyCompound);
+    }
+
+    // a single shape doesn't need a compound
+    if parts.len() == 1 && parts[0].0 == Isometry3::identity() {
+        return Ok(parts.remove(0).1);
+    }
+
+    Ok(SharedShape::compound(parts))
+}
+
+/// Sent when a collider can't be generated from a mesh or an image
+#[derive(Event, Debug)]
+pub struct ColliderGenerationFailed {
+    /// The entity the collider was generated for
+    pub entity: Entity,
+    pub reason: ColliderGenerationError,
+}
+
+/// The meshes a MeshCollider or SceneCollider was generated from.
+/// The collider is rebuilt when one of them is modified, e.g. when the asset is hot-reloaded
 #[derive(Component)]
-pub struct MeshCollider(pub Handle<Mesh>);
+pub struct ColliderSource {
+    pub meshes: Vec<Handle<Mesh>>,
+}
 
-// Generates a trimesh Collider from a mesh
+/// A collider generated from a mesh.
+/// It stays on the entity after the Collider is built, so it can be rebuilt when the mesh changes
+#[derive(Component)]
+pub struct MeshCollider {
+    pub mesh: Handle<Mesh>,
+    pub mode: MeshColliderMode,
+}
+
+impl MeshCollider {
+    /// A collider matching the mesh exactly
+    pub fn new(mesh: Handle<Mesh>) -> Self {
+        MeshCollider {
+            mesh,
+            mode: MeshColliderMode::Trimesh,
+        }
+    }
+
+    pub fn with_mode(mut self, mode: MeshColliderMode) -> Self {
+        self.mode = mode;
+        self
+    }
+}
+
+/// How a MeshCollider approximates its mesh
+#[derive(Clone, Default, PartialEq)]
+pub enum MeshColliderMode {
+    /// A triangle mesh matching the mesh exactly
+    #[default]
+    Trimesh,
+    /// A box aligned with the mesh's local axes
+    Aabb,
+    /// A box aligned with the principal axes of the vertices
+    Obb,
+    /// The smallest sphere enclosing the vertices
+    BoundingSphere,
+    /// A capsule along the longest principal axis of the vertices
+    Capsule,
+    /// A compound of convex hulls, used to simulate concave meshes as dynamic bodies
+    ConvexDecomposition(VHACDParameters),
+}
+
+// Marks the colliders generated from modified meshes to be rebuilt.
+// The old Collider is kept until the new one replaces it, so the body keeps its velocity
+fn reload_mesh_colliders(
+    mut mesh_events: EventReader<AssetEvent<Mesh>>,
+    source_q: Query<(Entity, &ColliderSource)>,
+    mut commands: Commands
+) {
+    let modified: Vec<AssetId<Mesh>> = mesh_events.read()
+        .filter_map(|event| match event {
+            AssetEvent::Modified { id } => Some(*id),
+            _ => None,
+        })
+        .collect();
+
+    if modified.is_empty() {
+        return;
+    }
+
+    for (ent, source) in source_q.iter() {
+        if source.meshes.iter().any(|mesh| modified.contains(&mesh.id())) {
+            commands.entity(ent).remove::<ColliderSource>();
+        }
+    }
+}
+
+
+// Generates a Collider from a mesh.
+// Entities using the same mesh and mode share the same shape
 fn assign_mesh_collider(
-    collider_q: Query<(Entity, &MeshCollider)>,
+    collider_q: Query<(Entity, &MeshCollider), Without<ColliderSource>>,
     meshes: Res<Assets<Mesh>>,
+    mut mesh_events: EventReader<AssetEvent<Mesh>>,
+    mut shapes: Local<HashMap<AssetId<Mesh>, Vec<(MeshColliderMode, SharedShape)>>>,
+    mut failed: EventWriter<ColliderGenerationFailed>,
     mut commands: Commands
 ) {
-    for (ent, mesh_handle) in collider_q.iter() {
+    // the shapes of modified meshes are stale
+    for event in mesh_events.read() {
+        if let AssetEvent::Modified { id } | AssetEvent::Removed { id } = event {
+            shapes.remove(id);
+        }
+    }
+
+    for (ent, mesh_collider) in collider_q.iter() {
 
 
-        if let Some(mesh) = meshes.get(&mesh_handle.0) {
+        if let Some(mesh) = meshes.get(&mesh_collider.mesh) {
 
+            // also kept if the generation fails, so a fixed mesh is retried
+            commands.entity(ent).insert(ColliderSource {
+                meshes: vec![mesh_collider.mesh.clone()]
+            });
 
-            let verts_idxs = extract_mesh_vertices_indices(mesh).unwrap();
+            let mesh_shapes = shapes.entry(mesh_collider.mesh.id()).or_default();
 
-            let shared_shape = SharedShape::trimesh(
-                verts_idxs.verts, 
-                verts_idxs.indices, 
-            );
+            let shared_shape = match mesh_shapes.iter().find(|(mode, _)| *mode == mesh_collider.mode) {
+                Some((_, shape)) => shape.clone(),
+                None => {
+                    let shape = match build_mesh_shape(mesh, &mesh_collider.mode) {
+                        Ok(shape) => shape,
+                        Err(reason) => {
+                            failed.send(ColliderGenerationFailed { entity: ent, reason });
+                            continue;
+                        }
+                    };
+                    mesh_shapes.push((mesh_collider.mode.clone(), shape.clone()));
 
-            let compound_collider = Box::new(shared_shape.as_trimesh().unwrap().clone());
+                    shape
+                }
+            };
 
             
-            commands.entity(ent).remove::<MeshCollider>();
             commands.entity(ent).insert(Collider {
-                shape: compound_collider
+                shape: shared_shape
             });
 
 
@@ -57,3 +457,341 @@ fn assign_mesh_collider(
     }
 
 }
+
+
+// Builds the shape approximating the mesh in the given mode
+fn build_mesh_shape(mesh: &Mesh, mode: &MeshColliderMode) -> Result<SharedShape, ColliderGenerationError> {
+    let verts_idxs = extract_mesh_vertices_indices(mesh)?;
+
+    Ok(build_shape(verts_idxs, mode))
+}
+
+// Builds the shape approximating the vertices and indices in the given mode
+fn build_shape(verts_idxs: VerticesIndices, mode: &MeshColliderMode) -> SharedShape {
+    match mode {
+        MeshColliderMode::Trimesh => SharedShape::trimesh(
+            verts_idxs.verts, 
+            verts_idxs.indices, 
+        ),
+        MeshColliderMode::Aabb => fit_aabb(&verts_idxs.verts),
+        MeshColliderMode::Obb => fit_obb(&verts_idxs.verts),
+        MeshColliderMode::BoundingSphere => fit_bounding_sphere(&verts_idxs.verts),
+        MeshColliderMode::Capsule => fit_capsule(&verts_idxs.verts),
+        MeshColliderMode::ConvexDecomposition(params) => SharedShape::convex_decomposition_with_params(
+            &verts_idxs.verts, 
+            &verts_idxs.indices, 
+            params
+        ),
+    }
+}
+
+
+/// Generates a collider for the meshes of the scene spawned by this entity's SceneRoot.
+/// Once the scene is ready the meshes are merged into a single Collider on this entity,
+/// each placed with its transform relative to it.
+/// The Collider is rebuilt when one of the meshes changes
+#[derive(Component, Default)]
+pub struct SceneCollider {
+    pub mode: MeshColliderMode,
+    /// If set, only the meshes whose name, or the name of one of their ancestors, contains it get a collider
+    pub name_filter: Option<String>,
+}
+
+// Generates the Collider of a scene once it has spawned
+fn assign_scene_collider(
+    scene_q: Query<(Entity, &SceneCollider, &SceneInstance), Without<ColliderSource>>,
+    node_q: Query<(Option<&Children>, Option<&Transform>, Option<&Name>, Option<&Mesh3d>)>,
+    scene_spawner: Res<SceneSpawner>,
+    meshes: Res<Assets<Mesh>>,
+    mut failed: EventWriter<ColliderGenerationFailed>,
+    mut commands: Commands
+) {
+    for (ent, scene_collider, instance) in scene_q.iter() {
+
+        if !scene_spawner.instance_is_ready(**instance) {
+            continue;
+        }
+
+        // collects the meshes of the scene, the root's transform is the body's transform
+        let mut scene_meshes = Vec::new();
+
+        if let Ok((Some(children), ..)) = node_q.get(ent) {
+            for child in children.iter() {
+                collect_scene_meshes(
+                    *child, 
+                    Transform::IDENTITY, 
+                    scene_collider.name_filter.is_none(), 
+                    scene_collider, 
+                    &node_q, 
+                    &mut scene_meshes
+                );
+            }
+        }
+
+        // waits for all the meshes to load
+        let Some(parts) = scene_meshes.iter()
+            .map(|(mesh_ent, handle, transform)| Some((*mesh_ent, meshes.get(handle)?, *transform)))
+            .collect::<Option<Vec<_>>>() else {
+            continue;
+        };
+
+        commands.entity(ent).insert(ColliderSource {
+            meshes: scene_meshes.iter().map(|(_, handle, _)| handle.clone()).collect()
+        });
+
+        match build_scene_shape(&parts, &scene_collider.mode, &mut failed) {
+            Ok(shape) => {
+                commands.entity(ent).insert(Collider {
+                    shape
+                });
+            }
+            Err(reason) => {
+                failed.send(ColliderGenerationFailed { entity: ent, reason });
+            }
+        }
+    }
+}
+
+
+// Walks the scene hierarchy collecting the meshes with their transform relative to the root
+fn collect_scene_meshes(
+    entity: Entity,
+    parent_transform: Transform,
+    parent_matches: bool,
+    scene_collider: &SceneCollider,
+    node_q: &Query<(Option<&Children>, Option<&Transform>, Option<&Name>, Option<&Mesh3d>)>,
+    scene_meshes: &mut Vec<(Entity, Handle<Mesh>, Transform)>,
+) {
+    let Ok((children, transform, name, mesh)) = node_q.get(entity) else {
+        return;
+    };
+
+    let transform = parent_transform.mul_transform(transform.copied().unwrap_or_default());
+
+    let matches = parent_matches || match (&scene_collider.name_filter, name) {
+        (Some(filter), Some(name)) => name.as_str().contains(filter.as_str()),
+        _ => false,
+    };
+
+    if let (Some(mesh), true) = (mesh, matches) {
+        scene_meshes.push((entity, mesh.0.clone(), transform));
+    }
+
+    if let Some(children) = children {
+        for child in children.iter() {
+            collect_scene_meshes(*child, transform, matches, scene_collider, node_q, scene_meshes);
+        }
+    }
+}
+
+
+// Builds a single shape out of the meshes of a scene.
+// The vertices are moved into the root's space, so the scale of the nodes is kept.
+// The meshes that can't be used are reported and skipped
+fn build_scene_shape(
+    parts: &[(Entity, &Mesh, Transform)], 
+    mode: &MeshColliderMode,
+    failed: &mut EventWriter<ColliderGenerationFailed>,
+) -> Result<SharedShape, ColliderGenerationError> {
+    let parts: Vec<VerticesIndices> = parts.iter()
+        .filter_map(|(mesh_ent, mesh, transform)| {
+            let mut verts_idxs = match extract_mesh_vertices_indices(mesh) {
+                Ok(verts_idxs) => verts_idxs,
+                Err(reason) => {
+                    failed.send(ColliderGenerationFailed { entity: *mesh_ent, reason });
+                    return None;
+                }
+            };
+
+            for vert in verts_idxs.verts.iter_mut() {
+                let point = transform.transform_point(Vec3::new(vert.x, vert.y, vert.z));
+                *vert = vec3_to_point(point);
+            }
+
+            Some(verts_idxs)
+        })
+        .collect();
+
+    if parts.is_empty() {
+        return Err(ColliderGenerationError::NoSceneMeshes);
+    }
+
+    match mode {
+        // trimeshes can't be part of a compound, so the meshes are merged into one
+        MeshColliderMode::Trimesh => {
+            let mut merged = VerticesIndices {
+                verts: Vec::new(),
+                indices: Vec::new(),
+            };
+
+            for part in parts {
+                let offset = merged.verts.len() as u32;
+
+                merged.indices.extend(part.indices.iter().map(|[a, b, c]| [a + offset, b + offset, c + offset]));
+                merged.verts.extend(part.verts);
+            }
+
+            Ok(build_shape(merged, mode))
+        }
+        _ => {
+            let shapes = parts.into_iter()
+                .map(|part| (Isometry3::identity(), build_shape(part, mode)))
+                .collect();
+
+            compound_shape(shapes)
+        }
+    }
+}
+
+
+// Merges the colliders of the children of a RigidBody into the body's Collider,
+// each placed with its transform relative to the body
+fn merge_child_colliders(
+    changed_q: Query<Entity, (
+        With<Collider>, 
+        With<Parent>, 
+        Without<RigidBody>, 
+        Or<(Changed<Collider>, Changed<Transform>, Changed<Parent>)>
+    )>,
+    mut removed: RemovedComponents<Collider>,
+    mut removed_parents: RemovedComponents<Parent>,
+    changed_own_q: Query<Entity, Changed<OwnCollider>>,
+    parent_q: Query<&Parent>,
+    body_q: Query<(Option<&Collider>, Option<&OwnCollider>), With<RigidBody>>,
+    node_q: Query<(Option<&Children>, Option<&Transform>, Option<&Collider>), Without<RigidBody>>,
+    children_q: Query<&Children>,
+    mut child_bodies: Local<HashMap<Entity, Entity>>,
+    mut failed: EventWriter<ColliderGenerationFailed>,
+    mut commands: Commands
+) {
+    let mut dirty_bodies = Vec::new();
+
+    for child in changed_q.iter() {
+        // the body is the closest ancestor with a RigidBody
+        let Some(body) = parent_q.iter_ancestors(child).find(|ancestor| body_q.contains(*ancestor)) else {
+            continue;
+        };
+
+        // the child could have moved to another body
+        if let Some(old_body) = child_bodies.insert(child, body) {
+            dirty_bodies.push(old_body);
+        }
+        dirty_bodies.push(body);
+    }
+
+    for child in removed.read() {
+        if let Some(body) = child_bodies.remove(&child) {
+            dirty_bodies.push(body);
+        }
+    }
+
+    // a child, or one of its ancestors, was detached from the hierarchy, so it could have left its body
+    if removed_parents.read().count() > 0 {
+        let detached: Vec<Entity> = child_bodies.iter()
+            .filter(|(child, body)| !parent_q.iter_ancestors(**child).any(|ancestor| ancestor == **body))
+            .map(|(child, _)| *child)
+            .collect();
+
+        for child in detached {
+            if let Some(body) = child_bodies.remove(&child) {
+                dirty_bodies.push(body);
+            }
+        }
+    }
+
+    dirty_bodies.extend(changed_own_q.iter());
+
+    dirty_bodies.sort_unstable();
+    dirty_bodies.dedup();
+
+    for body in dirty_bodies {
+        let Ok((collider, own_collider)) = body_q.get(body) else {
+            continue;
+        };
+        let Some(mut body_commands) = commands.get_entity(body) else {
+            continue;
+        };
+
+        // the first merge keeps the body's own collider, which is replaced by the compound
+        let own_collider = match own_collider {
+            Some(own_collider) => own_collider.0.clone(),
+            None => {
+                body_commands.insert(OwnCollider(collider.cloned()));
+                collider.cloned()
+            }
+        };
+
+        let mut parts = Vec::new();
+
+        if let Ok(children) = children_q.get(body) {
+            for child in children.iter() {
+                collect_child_colliders(*child, Transform::IDENTITY, &node_q, &mut parts, &mut failed);
+            }
+        }
+
+        let merged = match parts.is_empty() {
+            true => None,
+            false => {
+                if let Some(own_collider) = &own_collider {
+                    parts.insert(0, (Isometry3::identity(), own_collider.shape.clone()));
+                }
+
+                compound_shape(parts)
+                    .map_err(|reason| failed.send(ColliderGenerationFailed { entity: body, reason }))
+                    .ok()
+            }
+        };
+
+        match (merged, own_collider) {
+            (Some(shape), _) => {
+                body_commands.insert(Collider { shape });
+            }
+            (None, Some(own_collider)) => {
+                body_commands.insert(own_collider);
+            }
+            (None, None) => {
+                body_commands.remove::<Collider>();
+            }
+        }
+    }
+}
+
+
+// Walks the children of a body collecting their colliders, stopping at nested bodies.
+// The trimeshes and heightfields can't be part of a compound, so they are reported and skipped
+fn collect_child_colliders(
+    entity: Entity,
+    parent_transform: Transform,
+    node_q: &Query<(Option<&Children>, Option<&Transform>, Option<&Collider>), Without<RigidBody>>,
+    parts: &mut Vec<(Isometry3<f32>, SharedShape)>,
+    failed: &mut EventWriter<ColliderGenerationFailed>,
+) {
+    let Ok((children, transform, collider)) = node_q.get(entity) else {
+        return;
+    };
+
+    let transform = parent_transform.mul_transform(transform.copied().unwrap_or_default());
+
+    if let Some(collider) = collider {
+        let composite = collider.shape.as_compound().is_none() && collider.shape.as_composite_shape().is_some();
+
+        if composite || collider.shape.as_heightfield().is_some() {
+            failed.send(ColliderGenerationFailed {
+                entity,
+                reason: ColliderGenerationError::NestedComposite,
+            });
+        }
+        else {
+            parts.push((
+                transform_to_isometry(transform), 
+                ScaledShape::new(&collider.shape, transform.scale).shape
+            ));
+        }
+    }
+
+    if let Some(children) = children {
+        for child in children.iter() {
+            collect_child_colliders(*child, transform, node_q, parts, failed);
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use std::ops::Range;
+
+use bevy::prelude::*;
+use parry3d::shape::{HeightField, SharedShape};
+
+use super::collider::{Collider, ColliderGenerationFailed};
+use super::ColliderGenerationError;
+
+
+/// A heightfield collider generated from a grayscale image.
+/// Each pixel is a point of the heightfield, from 0 (black) to 1 (white) before scaling
+#[derive(Component)]
+pub struct HeightfieldCollider {
+    pub image: Handle<Image>,
+    /// The heightfield is scale.x wide, scale.z deep and scale.y high
+    pub scale: Vec3,
+}
+
+
+impl Collider {
+    /// A heightfield built from the pixels of a grayscale image.
+    /// The image rows go along Z and its columns along X.
+    /// Returns None if the image format can't be read or the image is smaller than 2x2 pixels
+    pub fn heightfield_from_image(image: &Image, scale: Vec3) -> Option<Self> {
+        let size = image.size();
+
+        if size.x < 2 || size.y < 2 {
+            return None;
+        }
+
+        let mut heights = Vec::with_capacity(size.y as usize);
+
+        for y in 0..size.y {
+            let mut row = Vec::with_capacity(size.x as usize);
+
+            for x in 0..size.x {
+                // the stored value of the pixel, without any color space conversion
+                let height = match image.get_color_at(x, y).ok()? {
+                    Color::Srgba(color) => color.red,
+                    color => color.to_linear().red,
+                };
+
+                row.push(height);
+            }
+
+            heights.push(row);
+        }
+
+        Collider::heightfield(&heights, scale).ok()
+    }
+
+    /// Edits the heights of a region of a heightfield collider.
+    /// edit is called with the row, column and current height of every point in the region
+    /// and returns its new height, before scaling.
+    /// parry only gives read access to the heights, so the whole heightfield is rebuilt,
+    /// which costs as much as creating it however small the region is.
+    /// Returns false if the collider isn't a heightfield
+    pub fn edit_heights(
+        &mut self,
+        rows: Range<usize>,
+        columns: Range<usize>,
+        mut edit: impl FnMut(usize, usize, f32) -> f32
+    ) -> bool {
+        let Some(heightfield) = self.shape.as_heightfield() else {
+            return false;
+        };
+
+        let mut heights = heightfield.heights().clone();
+
+        for row in rows.start..rows.end.min(heights.nrows()) {
+            for column in columns.start..columns.end.min(heights.ncols()) {
+                heights[(row, column)] = edit(row, column, heights[(row, column)]);
+            }
+        }
+
+        // the bounds of the heightfield are recomputed from the new heights, the cell statuses are kept
+        let mut edited = HeightField::with_flags(heights, *heightfield.scale(), heightfield.flags());
+        *edited.cells_statuses_mut() = heightfield.cells_statuses().clone();
+
+        self.shape = SharedShape::new(edited);
+
+        true
+    }
+
+    /// The row and column of the heightfield cell closest to a point in the collider's local space,
+    /// useful to find the region to edit. Returns None if the collider isn't a heightfield
+    pub fn heightfield_cell_at(&self, local_point: Vec3) -> Option<(usize, usize)> {
+        let heightfield = self.shape.as_heightfield()?;
+
+        Some(heightfield.closest_cell_at_point(&[local_point.x, local_point.y, local_point.z].into()))
+    }
+}
+
+
+// Generates the heightfield Collider once the image has loaded
+pub fn assign_heightfield_collider(
+    heightfield_q: Query<(Entity, &HeightfieldCollider)>,
+    images: Res<Assets<Image>>,
+    mut failed: EventWriter<ColliderGenerationFailed>,
+    mut commands: Commands
+) {
+    for (ent, heightfield_collider) in heightfield_q.iter() {
+
+        if let Some(image) = images.get(&heightfield_collider.image) {
+
+            commands.entity(ent).remove::<HeightfieldCollider>();
+
+            match Collider::heightfield_from_image(image, heightfield_collider.scale) {
+                Some(collider) => {
+                    commands.entity(ent).insert(collider);
+                }
+                None => {
+                    failed.send(ColliderGenerationFailed {
+                        entity: ent,
+                        reason: ColliderGenerationError::UnsupportedImage,
+                    });
+                }
+            }
+        }
+    }
+}
+
@@ -1,22 +1,24 @@
-use std::rc::Rc;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashSet};
 
 use bevy::prelude::*;
-use parry3d::{bounding_volume::{Aabb, BoundingVolume}, shape::Shape};
+use parry3d::bounding_volume::{Aabb, BoundingVolume};
+use parry3d::query::{Ray, RayCast};
 
 
-use super::{subdivide_aabb, transform_to_isometry};
+use super::subdivide_aabb;
 
 pub struct OctreeNode {
     children: Option<[Box<OctreeNode>; 8]>, // None if this is a leaf node
     bounding_box: Aabb,
-    pub objects: Vec<Rc<PhysicsEntity>>,
+    pub objects: Vec<PhysicsEntity>,
     pub chunk_num: i32
 }
 
+#[derive(Clone, Copy)]
 pub struct PhysicsEntity {
     pub entity: Entity,             // the bevy entity
-    pub collider: Box<dyn Shape>,   // the collider shape
-    pub transform: Transform,       // the bevy transform
+    pub aabb: Aabb,                 // the world space aabb of the collider
 }
 
 #[derive(Component)]
@@ -24,30 +26,45 @@ pub struct Chunk(
     pub Vec<i32>
 );
 
+/// The bounds and occupancy of an octree leaf
+pub struct ChunkBounds {
+    pub bounding_box: Aabb,
+    pub chunk_num: i32,
+    pub occupancy: usize,
+}
+
+/// The octree built during the last broad phase.
+/// Can be used to run proximity queries on the physics entities
+#[derive(Resource)]
+pub struct Octree(pub OctreeNode);
+
+impl Default for Octree {
+    fn default() -> Self {
+        Octree(OctreeNode::new(Aabb::new_invalid()))
+    }
+}
+
 pub static mut CHUNK_NUMBER: i32 = 0;
 
 impl OctreeNode {
     pub fn insert(
         &mut self, 
-        physics_entity: Rc<PhysicsEntity>,   // the PhysicsEntity to insert
+        physics_entity: PhysicsEntity,   // the PhysicsEntity to insert
         max_objects: usize,             // the max number of objects in a node
         max_depth: usize,                // the max depth of a node
         chunk_query: &mut Query<&mut Chunk>,
         commands: &mut Commands
     ) {
 
-        let entity_isometry = transform_to_isometry(physics_entity.transform);
-        let collider = physics_entity.collider.compute_aabb(&entity_isometry).clone();
-        
         // if the node has children try inserting into one of them
         if let Some(children) = &mut self.children {
 
             for child in children.iter_mut() {
 
                 if child.bounding_box.intersects(
-                    &collider
+                    &physics_entity.aabb
                 ) {
-                    child.insert(physics_entity.clone(), max_objects, max_depth - 1, chunk_query, commands);
+                    child.insert(physics_entity, max_objects, max_depth - 1, chunk_query, commands);
                 }
             }
 
@@ -113,9 +130,6 @@ impl OctreeNode {
 
         // Redistribute the objects into the appropriate child nodes
         for physics_entity in entities {
-            // converts the bevy Transform to a parry3d Isometry for this entity
-            let entity_isometry = transform_to_isometry(physics_entity.transform);
-
             // removes the current chunk from the entity to be reassigned
             if let Ok(mut chunks) = chunk_query.get_mut(physics_entity.entity) {
                 let index = chunks.0.iter().position(|x| *x == self.chunk_num).unwrap();
@@ -125,12 +139,11 @@ impl OctreeNode {
             for child in self.children.as_mut().unwrap().iter_mut() {
                
                 // checks each entity and inserts it in a chunk
-                let collider = physics_entity.collider.compute_aabb(&entity_isometry).clone();
                 if child.bounding_box.intersects(
-                    &collider
+                    &physics_entity.aabb
                 ) {
 
-                    child.insert(physics_entity.clone(), max_objects, max_depth - 1, chunk_query, commands);
+                    child.insert(physics_entity, max_objects, max_depth - 1, chunk_query, commands);
                     
                 }
             }
@@ -150,4 +163,215 @@ impl OctreeNode {
         }
     }
 
+    /// Collects the bounds of all the leaves of this node
+    pub fn leaves(&self, leaves: &mut Vec<ChunkBounds>) {
+        match &self.children {
+            Some(children) => {
+                for child in children.iter() {
+                    child.leaves(leaves);
+                }
+            }
+            None => {
+                leaves.push(ChunkBounds {
+                    bounding_box: self.bounding_box,
+                    chunk_num: self.chunk_num,
+                    occupancy: self.objects.len(),
+                });
+            }
+        }
+    }
+
+    /// Collects the entities whose aabb is within radius of the point
+    fn within_radius(&self, point: Vec3, radius: f32, entities: &mut HashSet<Entity>) {
+        if aabb_distance(&self.bounding_box, point) > radius {
+            return;
+        }
+
+        match &self.children {
+            Some(children) => {
+                for child in children.iter() {
+                    child.within_radius(point, radius, entities);
+                }
+            }
+            None => {
+                for physics_entity in self.objects.iter() {
+                    if aabb_distance(&physics_entity.aabb, point) <= radius {
+                        entities.insert(physics_entity.entity);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Collects the entities whose aabb is crossed by the ray before max_distance
+    fn along_ray(&self, ray: &Ray, max_distance: f32, entities: &mut HashSet<Entity>) {
+        if !self.bounding_box.intersects_local_ray(ray, max_distance) {
+            return;
+        }
+
+        match &self.children {
+            Some(children) => {
+                for child in children.iter() {
+                    child.along_ray(ray, max_distance, entities);
+                }
+            }
+            None => {
+                for physics_entity in self.objects.iter() {
+                    if physics_entity.aabb.intersects_local_ray(ray, max_distance) {
+                        entities.insert(physics_entity.entity);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Collects the entities in the leaves containing the entity
+    fn sharing_chunk(&self, entity: Entity, entities: &mut HashSet<Entity>) {
+        match &self.children {
+            Some(children) => {
+                for child in children.iter() {
+                    child.sharing_chunk(entity, entities);
+                }
+            }
+            None => {
+                if self.objects.iter().any(|physics_entity| physics_entity.entity == entity) {
+                    entities.extend(self.objects.iter().map(|physics_entity| physics_entity.entity));
+                }
+            }
+        }
+    }
+
+}
+
+
+impl Octree {
+    /// Returns the entities whose collider aabb is within radius of the point
+    pub fn entities_within_radius(&self, point: Vec3, radius: f32) -> Vec<Entity> {
+        let mut entities = HashSet::new();
+        self.0.within_radius(point, radius, &mut entities);
+
+        entities.into_iter().collect()
+    }
+
+    /// Returns the k entities closest to the point with their distance, nearest first.
+    /// The distance is measured to the collider aabb
+    pub fn k_nearest(&self, point: Vec3, k: usize) -> Vec<(Entity, f32)> {
+        let mut nearest = Vec::new();
+        let mut found = HashSet::new();
+
+        // visits the nodes and entities from the closest to the farthest
+        let mut queue = BinaryHeap::new();
+        queue.push(QueueItem {
+            distance: aabb_distance(&self.0.bounding_box, point),
+            item: OctreeItem::Node(&self.0),
+        });
+
+        while let Some(QueueItem { distance, item }) = queue.pop() {
+            if nearest.len() >= k {
+                break;
+            }
+
+            match item {
+                OctreeItem::Node(node) => {
+                    if let Some(children) = &node.children {
+                        for child in children.iter() {
+                            queue.push(QueueItem {
+                                distance: aabb_distance(&child.bounding_box, point),
+                                item: OctreeItem::Node(child),
+                            });
+                        }
+                    }
+
+                    for physics_entity in node.objects.iter() {
+                        queue.push(QueueItem {
+                            distance: aabb_distance(&physics_entity.aabb, point),
+                            item: OctreeItem::Entity(physics_entity.entity),
+                        });
+                    }
+                }
+                OctreeItem::Entity(entity) => {
+                    // an entity can be stored in more than one leaf
+                    if found.insert(entity) {
+                        nearest.push((entity, distance));
+                    }
+                }
+            }
+        }
+
+        nearest
+    }
+
+    /// Returns the entities whose collider aabb is crossed by the ray before max_distance.
+    /// The direction must be normalized
+    pub fn entities_along_ray(&self, origin: Vec3, direction: Vec3, max_distance: f32) -> Vec<Entity> {
+        let ray = Ray::new(
+            [origin.x, origin.y, origin.z].into(),
+            [direction.x, direction.y, direction.z].into()
+        );
+
+        let mut entities = HashSet::new();
+        self.0.along_ray(&ray, max_distance, &mut entities);
+
+        entities.into_iter().collect()
+    }
+
+    /// Returns the entities sharing at least one chunk with the entity
+    pub fn entities_sharing_chunk(&self, entity: Entity) -> Vec<Entity> {
+        let mut entities = HashSet::new();
+        self.0.sharing_chunk(entity, &mut entities);
+        entities.remove(&entity);
+
+        entities.into_iter().collect()
+    }
+
+    /// Collects the bounds of all the leaves of the octree
+    pub fn leaves(&self) -> Vec<ChunkBounds> {
+        let mut leaves = Vec::new();
+        self.0.leaves(&mut leaves);
+
+        leaves
+    }
+}
+
+
+// An item of the k nearest search, either a node to visit or an entity found
+enum OctreeItem<'a> {
+    Node(&'a OctreeNode),
+    Entity(Entity),
+}
+
+// Orders the items of the k nearest search by distance, nearest first
+struct QueueItem<'a> {
+    distance: f32,
+    item: OctreeItem<'a>,
+}
+
+impl PartialEq for QueueItem<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.distance == other.distance
+    }
+}
+
+impl Eq for QueueItem<'_> {}
+
+impl PartialOrd for QueueItem<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for QueueItem<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // reversed, so that the BinaryHeap pops the nearest item first
+        other.distance.total_cmp(&self.distance)
+    }
+}
+
+
+// The distance between a point and an aabb, 0 if the point is inside it
+fn aabb_distance(aabb: &Aabb, point: Vec3) -> f32 {
+    let mins = Vec3::new(aabb.mins.x, aabb.mins.y, aabb.mins.z);
+    let maxs = Vec3::new(aabb.maxs.x, aabb.maxs.y, aabb.maxs.z);
+
+    point.distance(point.max(mins).min(maxs))
 }
@@ -1,9 +1,9 @@
-use std::collections::HashSet;
-use std::rc::Rc;
+use std::collections::{HashMap, HashSet};
 
-use super::{Mass, RigidBody, Velocity};
+use super::{joints::Joint, mass::MassProperties, LockedAxes, RigidBody, Velocity};
 
 use bevy::prelude::*;
+use bevy::tasks::{ComputeTaskPool, ParallelSlice};
 
 use nalgebra::{Isometry3, Point3};
 use parry3d::bounding_volume::{Aabb, BoundingVolume};
@@ -11,29 +11,84 @@ use parry3d::query::Contact;
 use parry3d::query;
 
 #[path = "./utils/utils.rs"]
-mod utils;
+pub mod utils;
 use utils::*;
 
+#[path = "./utils/fitting.rs"]
+mod fitting;
+
+#[path = "./utils/scaling.rs"]
+mod scaling;
+
 #[path = "./classes/collider.rs"]
 pub mod collider;
 use collider::*;
 
+#[path = "./classes/heightfield.rs"]
+pub mod heightfield;
+
 
 #[path = "./classes/octree.rs"]
-mod octree;
+pub mod octree;
 use octree::*;
 
-const MAX_ENTITIES: usize = 50; 
+pub const MAX_ENTITIES: usize = 50; 
 const MAX_DEPTH: usize = 5;
 
 pub const TOLERANCE: f32 = 0.0;
 
+const SCALE_TOLERANCE: f32 = 1e-5;
+
+// how much a contact normal must point along the pass through direction of a one way platform
+// for the body to be on its solid side, where it's blocked even when moving along that direction
+const ONE_WAY_MIN_NORMAL: f32 = 0.5;
+
+
+/// Rebuilds the scaled shapes of the colliders whose scale or shape changed
+pub fn update_scaled_shapes(
+    mut query: Query<
+        (Entity, Ref<Collider>, &GlobalTransform, Option<&mut ScaledShape>), 
+        (With<RigidBody>, Or<(Changed<Collider>, Changed<GlobalTransform>)>)
+    >,
+    mut commands: Commands,
+) {
+    for (entity, collider, global_transform, scaled_shape) in query.iter_mut() {
+        let (scale, _, _) = global_transform.to_scale_rotation_translation();
+
+        match scaled_shape {
+            Some(mut scaled_shape) => {
+                // the scale is compared with a tolerance since it's extracted from the global transform
+                if collider.is_changed() || !scaled_shape.scale.abs_diff_eq(scale, SCALE_TOLERANCE) {
+                    *scaled_shape = ScaledShape::new(&collider.shape, scale);
+                }
+            }
+            None => {
+                commands.entity(entity).insert(ScaledShape::new(&collider.shape, scale));
+            }
+        }
+    }
+}
+
+
+/// Recomputes the world space aabbs of the colliders that moved or changed shape
+pub fn update_collider_aabbs(
+    mut query: Query<
+        (&ScaledShape, &GlobalTransform, &mut ColliderAabb), 
+        (With<RigidBody>, Or<(Changed<ScaledShape>, Changed<GlobalTransform>)>)
+    >,
+) {
+    for (scaled_shape, global_transform, mut aabb) in query.iter_mut() {
+        aabb.0 = scaled_shape.shape.compute_aabb(&global_transform_to_isometry(global_transform));
+    }
+}
+
 
 /// Handles the broad phase collision detection.
 /// spawns an entity containing the Broad Collison Groups
 pub fn broad_phase(
-    entity_query: Query<(Entity, &Collider, &Transform)>,
+    entity_query: Query<(Entity, &ColliderAabb), With<RigidBody>>,
     mut chunk_query: Query<&mut Chunk>,
+    mut octree: ResMut<Octree>,
     mut commands: Commands,
 
 ) {
@@ -43,147 +98,273 @@ pub fn broad_phase(
         Point3::new(50.0, 50.0, 50.0)
     );
 
-    let mut octree = OctreeNode::new(world_bounds);
+    let mut root = OctreeNode::new(world_bounds);
 
     unsafe {CHUNK_NUMBER = 0}
 
-    for (entity, collider, transform) in entity_query.iter() {
-        
+    for (entity, aabb) in entity_query.iter() {
         
-        
-        let physics_entity = Rc::new(PhysicsEntity {
+        let physics_entity = PhysicsEntity {
             entity: entity,
-            collider: collider.shape.clone_dyn(),
-            transform: *transform,
-        });
+            aabb: aabb.0,
+        };
 
 
         if let Ok(mut chunks) = chunk_query.get_mut(entity) {
             chunks.0 = Vec::new();
         }
-        octree.insert(physics_entity, MAX_ENTITIES, MAX_DEPTH, &mut chunk_query, &mut commands);    
+        root.insert(physics_entity, MAX_ENTITIES, MAX_DEPTH, &mut chunk_query, &mut commands);    
     }
 
+    octree.0 = root;
+
 }
 
 
 
-/// handles the narrow phase collision detection
-pub fn narrow_phase(
+/// A contact between two entities generated by the narrow phase
+#[derive(Clone, Copy)]
+pub struct ContactPair {
+    pub entity_1: Entity,
+    pub entity_2: Entity,
+    pub contact: Contact,
+}
 
-    mut query: Query<(
-        &mut Transform, 
+/// The contacts generated by the narrow phase during the current tick
+#[derive(Resource, Default)]
+pub struct Contacts(pub Vec<ContactPair>);
+
+/// The (platform, body) pairs where the body is passing through a OneWayPlatform.
+/// A pair is kept until the body stops touching the platform, so it isn't pushed back halfway through
+#[derive(Resource, Default)]
+pub struct OneWayPassing(pub HashSet<(Entity, Entity)>);
+
+
+// A snapshot of the data the narrow phase needs for an entity
+struct NarrowPhaseBody<'a> {
+    entity: Entity,
+    isometry: Isometry3<f32>,
+    aabb: Aabb,
+    shape: &'a ScaledShape,
+    rigid_body: RigidBody,
+    sensor: bool,
+    layers: CollisionLayers,
+}
+
+
+/// handles the narrow phase collision detection.
+/// Contacts are computed in parallel and stored in the Contacts resource
+pub fn narrow_phase(
+    query: Query<(
+        Entity,
+        &GlobalTransform, 
         &Chunk, 
-        &Collider, 
+        &ScaledShape, 
+        &ColliderAabb, 
         &RigidBody, 
-        Option<&mut Velocity>,
-        Option<&Mass>
-    )>
+        Has<Sensor>,
+        Option<&CollisionLayers>,
+    )>,
+    joint_q: Query<&Joint>,
+    mut contacts: ResMut<Contacts>,
 ) {
 
-    // iterates all entities
-    let mut combinations = query.iter_combinations_mut();
-    while let Some([
-        (mut transform_1, chunk_1, collider_1, rigid_body_1, mut velocity_1, mass_1),
-        (mut transform_2, chunk_2, collider_2, rigid_body_2, mut velocity_2, mass_2)
-    ]) = combinations.fetch_next() {
+    // the bodies connected by a joint don't collide, unless the joint allows it
+    let connected: HashSet<(Entity, Entity)> = joint_q.iter()
+        .filter(|joint| !joint.collide_connected)
+        .map(|joint| (joint.body_1.min(joint.body_2), joint.body_1.max(joint.body_2)))
+        .collect();
 
+    let mut bodies = Vec::new();
+    let mut chunk_map: HashMap<i32, Vec<usize>> = HashMap::new();
 
-        let set: HashSet<_> = chunk_1.0.iter().collect();
-        
-        // if 2 entities are in the same chunk check for collisions
-        if chunk_2.0.iter().any(|item| set.contains(item)) {
+    for (entity, global_transform, chunk, shape, aabb, rigid_body, sensor, layers) in query.iter() {
+        let isometry = global_transform_to_isometry(global_transform);
 
-            let isometry_1 = transform_to_isometry(*transform_1);
-            let isometry_2 = transform_to_isometry(*transform_2);
+        // groups the entities by the chunks they belong to
+        for chunk_num in chunk.0.iter() {
+            chunk_map.entry(*chunk_num).or_default().push(bodies.len());
+        }
 
-            
-            let aabb_1 = collider_1.shape.compute_aabb(&isometry_1);
-            let aabb_2 = collider_2.shape.compute_aabb(&isometry_2);
-
-            // if the objects' aabbs intersect, they are close, so check for collisions
-            if aabb_1.intersects(&aabb_2) {
-
-                if let Some(contact) = query::intersection_test(
-                    &isometry_1, 
-                    &*collider_1.shape, 
-                    &isometry_2, 
-                    &*collider_2.shape, 
-                ).ok() {
-                    if contact {
-                        collision_check(
-                            isometry_1, 
-                            collider_1, 
-                            rigid_body_1, 
-                            &mut transform_1, 
-                            &mut velocity_1,
-                            mass_1,
-            
-                            isometry_2, 
-                            collider_2, 
-                            rigid_body_2, 
-                            &mut transform_2,
-                            &mut velocity_2,
-                            mass_2
-                        );
-                    }
+        bodies.push(NarrowPhaseBody {
+            entity,
+            isometry,
+            aabb: aabb.0,
+            shape,
+            rigid_body: *rigid_body,
+            sensor,
+            layers: layers.copied().unwrap_or_default(),
+        });
+    }
+
+    // if 2 entities are in the same chunk they are a candidate pair
+    let mut pairs = HashSet::new();
+    for indices in chunk_map.values() {
+        for (i, index_1) in indices.iter().enumerate() {
+            for index_2 in indices[i + 1..].iter() {
+                
+                // only the dynamic bodies get separated
+                if let (RigidBody::Static | RigidBody::Kinematic, RigidBody::Static | RigidBody::Kinematic) = 
+                    (bodies[*index_1].rigid_body, bodies[*index_2].rigid_body) {
+                    continue;
                 }
-            }
 
-            
-        }
+                // sensors only detect overlaps
+                if bodies[*index_1].sensor || bodies[*index_2].sensor {
+                    continue;
+                }
 
-        
+                if !bodies[*index_1].layers.interacts_with(&bodies[*index_2].layers) {
+                    continue;
+                }
+
+                let (entity_1, entity_2) = (bodies[*index_1].entity, bodies[*index_2].entity);
+                if connected.contains(&(entity_1.min(entity_2), entity_1.max(entity_2))) {
+                    continue;
+                }
+
+                pairs.insert((*index_1.min(index_2), *index_1.max(index_2)));
+            }
+        }
     }
-}
 
+    // sorted so that the contacts are always solved in the same order
+    let mut pairs: Vec<(usize, usize)> = pairs.into_iter().collect();
+    pairs.sort_unstable();
+
+    let results = pairs.par_splat_map(ComputeTaskPool::get(), None, |_, chunk| {
+        chunk.iter()
+            .filter_map(|(index_1, index_2)| collision_check(&bodies[*index_1], &bodies[*index_2]))
+            .collect::<Vec<_>>()
+    });
+
+    contacts.0 = results.into_iter().flatten().collect();
+}
 
 
 
+/// Computes the contact between two entities, if any
 fn collision_check(
-    isometry_1: Isometry3<f32>,
-    collider_1: &Collider,
-    rigid_body_1: &RigidBody,
-    transform_1: &mut Transform,
-    velocity_1: &mut Option<Mut<'_, Velocity>>,
-    mass_1: Option<&Mass>,
+    body_1: &NarrowPhaseBody,
+    body_2: &NarrowPhaseBody,
+) -> Option<ContactPair> {
 
-    isometry_2: Isometry3<f32>,
-    collider_2: &Collider,
-    rigid_body_2: &RigidBody,
-    transform_2: &mut Transform,
-    velocity_2: &mut Option<Mut<'_, Velocity>>,
-    mass_2: Option<&Mass>,
+    // if the objects' aabbs don't intersect, they are too far to collide
+    if !body_1.aabb.intersects(&body_2.aabb) {
+        return None;
+    }
 
-) {
-    if let Some(contact) = query::contact(
-        &isometry_1, 
-        &*collider_1.shape, 
-        &isometry_2, 
-        &*collider_2.shape, 
+    let contact = query::contact(
+        &body_1.isometry, 
+        &*body_1.shape.shape, 
+        &body_2.isometry, 
+        &*body_2.shape.shape, 
         0.
-    ).ok() {
-        match contact {
-            Some(contact) => {
-
-
-                contact_handling(
-                    contact, 
-                    &rigid_body_1, 
-                    transform_1, 
-                    velocity_1,
-                    mass_1,
-
-                    &rigid_body_2, 
-                    transform_2,
-                    velocity_2,
-                    mass_2
-                );
-
-            },
-            None => {}
+    ).ok()??;
+
+    Some(ContactPair {
+        entity_1: body_1.entity,
+        entity_2: body_2.entity,
+        contact,
+    })
+}
+
+
+/// Applies the contacts generated by the narrow phase.
+/// The bodies are moved in world space
+pub fn solve_contacts(
+    contacts: Res<Contacts>,
+    mut passing: ResMut<OneWayPassing>,
+    mut query: Query<(
+        &mut GlobalTransform, 
+        &RigidBody, 
+        Option<&mut Velocity>,
+        Option<&MassProperties>,
+        Option<&LockedAxes>,
+        Option<&OneWayPlatform>
+    )>
+) {
+    // the platform and body pairs still touching, the others stopped passing through
+    let mut touching = HashSet::new();
+
+    for contact_pair in contacts.0.iter() {
+        if let Ok([
+            (mut global_transform_1, rigid_body_1, mut velocity_1, mass_1, locked_axes_1, platform_1),
+            (mut global_transform_2, rigid_body_2, mut velocity_2, mass_2, locked_axes_2, platform_2)
+        ]) = query.get_many_mut([contact_pair.entity_1, contact_pair.entity_2]) {
+
+            // the contacts of a body passing through a one way platform are ignored
+            let one_way = match (platform_1, platform_2) {
+                (Some(platform), None) => Some((
+                    (contact_pair.entity_1, contact_pair.entity_2),
+                    platform,
+                    &*global_transform_1,
+                    contact_pair.contact.normal1.xyz(),
+                    velocity_2.as_deref().map_or(Vec3::ZERO, |velocity| velocity.0) - velocity_1.as_deref().map_or(Vec3::ZERO, |velocity| velocity.0)
+                )),
+                (None, Some(platform)) => Some((
+                    (contact_pair.entity_2, contact_pair.entity_1),
+                    platform,
+                    &*global_transform_2,
+                    contact_pair.contact.normal2.xyz(),
+                    velocity_1.as_deref().map_or(Vec3::ZERO, |velocity| velocity.0) - velocity_2.as_deref().map_or(Vec3::ZERO, |velocity| velocity.0)
+                )),
+                _ => None,
+            };
+
+            if let Some((pair, platform, platform_transform, normal, relative_velocity)) = one_way {
+                touching.insert(pair);
+
+                let (_, rotation, _) = platform_transform.to_scale_rotation_translation();
+                let direction = (rotation * platform.direction).normalize_or_zero();
+
+                if passing.0.contains(&pair) || passes_through(direction, Vec3::new(normal.x, normal.y, normal.z), relative_velocity) {
+                    passing.0.insert(pair);
+                    continue;
+                }
+            }
+
+            let mut transform_1 = Transform::from_translation(global_transform_1.translation());
+            let mut transform_2 = Transform::from_translation(global_transform_2.translation());
+
+            contact_handling(
+                contact_pair.contact, 
+                rigid_body_1, 
+                &mut transform_1, 
+                &mut velocity_1,
+                mass_1,
+                locked_axes_1,
+
+                rigid_body_2, 
+                &mut transform_2,
+                &mut velocity_2,
+                mass_2,
+                locked_axes_2
+            );
+
+            // only the bodies that were separated get moved
+            let translation_1 = transform_1.translation - global_transform_1.translation();
+            let translation_2 = transform_2.translation - global_transform_2.translation();
+
+            if translation_1 != Vec3::ZERO {
+                translate_global_transform(&mut global_transform_1, translation_1);
+            }
+            if translation_2 != Vec3::ZERO {
+                translate_global_transform(&mut global_transform_2, translation_2);
+            }
         }
     }
+
+    passing.0.retain(|pair| touching.contains(pair));
+}
+
+
+/// If a body touching a one way platform is coming from the side it can pass through.
+/// The normal points from the platform to the body and the velocity is the body's relative to the platform.
+/// Only the bodies moving along the pass through direction get through, unless they are on the platform's solid side,
+/// so the bodies landing on its edges don't fall through
+pub fn passes_through(direction: Vec3, normal: Vec3, relative_velocity: Vec3) -> bool {
+    relative_velocity.dot(direction) > 0. && normal.dot(direction) <= ONE_WAY_MIN_NORMAL
 }
 
 
@@ -195,24 +376,26 @@ fn contact_handling(
     rigid_body_1: &RigidBody,
     transform_1: &mut Transform,
     velocity_1: &mut Option<Mut<'_, Velocity>>,
-    mass_1: Option<&Mass>,
+    mass_1: Option<&MassProperties>,
+    locked_axes_1: Option<&LockedAxes>,
 
 
     rigid_body_2: &RigidBody,
     transform_2: &mut Transform,
     velocity_2: &mut Option<Mut<'_, Velocity>>,
-    mass_2: Option<&Mass>,
+    mass_2: Option<&MassProperties>,
+    locked_axes_2: Option<&LockedAxes>,
 )  {
 
     match (rigid_body_1, rigid_body_2) {
-        (RigidBody::Static, RigidBody::Static) => {},
-        (RigidBody::Static, RigidBody::Dynamic) => {
+        (RigidBody::Static | RigidBody::Kinematic, RigidBody::Static | RigidBody::Kinematic) => {},
+        (RigidBody::Static | RigidBody::Kinematic, RigidBody::Dynamic) => {
             
-            separate_objects(transform_2, &mut velocity_2.as_mut().unwrap(), contact, 2);
+            separate_objects(transform_2, &mut velocity_2.as_mut().unwrap(), locked_axes_2, contact, 2);
         },
-        (RigidBody::Dynamic, RigidBody::Static) => {
+        (RigidBody::Dynamic, RigidBody::Static | RigidBody::Kinematic) => {
             
-            separate_objects(transform_1, &mut velocity_1.as_mut().unwrap(), contact, 1);
+            separate_objects(transform_1, &mut velocity_1.as_mut().unwrap(), locked_axes_1, contact, 1);
         },
         (RigidBody::Dynamic, RigidBody::Dynamic) => {
             let mut velocity_1 = velocity_1.as_mut().unwrap();
@@ -224,10 +407,12 @@ fn contact_handling(
             separate_dynamic(
                 transform_1, 
                 &mut velocity_1, 
-                mass_1.0, 
+                mass_1.mass, 
+                locked_axes_1,
                 transform_2, 
                 &mut velocity_2, 
-                mass_2.0, 
+                mass_2.mass, 
+                locked_axes_2,
                 contact
             );
         }
@@ -242,6 +427,7 @@ const RESTITUTION: f32 = 0.3;
 fn separate_objects(
     transform: &mut Transform,
     velocity: &mut Velocity,
+    locked_axes: Option<&LockedAxes>,
     contact: Contact,
     entity: i8
 ) {
@@ -263,7 +449,16 @@ fn separate_objects(
         normal.z
     );
         
-    let separation_vector = normal_vec * -contact.dist;
+    // the body can only be pushed along its free axes
+    let free_normal = locked_axes.map_or(normal_vec, |locked_axes| locked_axes.mask_translation(normal_vec));
+    let free_length_squared = free_normal.dot(normal_vec);
+
+    if free_length_squared <= f32::EPSILON {
+        return;
+    }
+
+    // moved along the free axes until the penetration along the normal is resolved
+    let separation_vector = free_normal * (-contact.dist / free_length_squared);
     
     // separate the objects
     transform.translation += separation_vector;
@@ -273,9 +468,9 @@ fn separate_objects(
     let normal_velocity = velocity.0.dot(normal_vec);
 
     if normal_velocity < 0.0 {
-        let impulse = (1.0 + RESTITUTION) * normal_velocity;
+        let impulse = (1.0 + RESTITUTION) * normal_velocity / free_length_squared;
 
-        velocity.0 -= impulse * normal_vec;
+        velocity.0 -= impulse * free_normal;
     }
 
 }
@@ -286,10 +481,12 @@ fn separate_dynamic(
     transform_1: &mut Transform,
     velocity_1: &mut Velocity,
     mass_1: f32,
+    locked_axes_1: Option<&LockedAxes>,
 
     transform_2: &mut Transform,
     velocity_2: &mut Velocity,
     mass_2: f32,
+    locked_axes_2: Option<&LockedAxes>,
 
     contact: Contact
 ) {
@@ -299,12 +496,22 @@ fn separate_dynamic(
     let normal = contact.normal1.xyz();
     let normal_vec = Vec3::new(normal.x, normal.y, normal.z);
 
-    // Resolve penetration by moving both objects
-    let separation_vector = normal_vec * -contact.dist;
-    let total_mass = mass_1 + mass_2;
+    // the inverse mass along each axis, zero along the locked ones
+    let inverse_mass_1 = locked_axes_1.map_or(Vec3::splat(1. / mass_1), |locked_axes| locked_axes.mask_translation(Vec3::splat(1. / mass_1)));
+    let inverse_mass_2 = locked_axes_2.map_or(Vec3::splat(1. / mass_2), |locked_axes| locked_axes.mask_translation(Vec3::splat(1. / mass_2)));
+
+    // how easily the pair moves apart along the normal
+    let weight = (normal_vec * normal_vec).dot(inverse_mass_1 + inverse_mass_2);
+
+    if weight <= f32::EPSILON {
+        return;
+    }
+
+    // Resolve penetration by moving both objects, the lighter one moves more
+    let separation = -contact.dist / weight;
 
-    transform_1.translation -= separation_vector * (mass_2 / total_mass);
-    transform_2.translation += separation_vector * (mass_1 / total_mass);
+    transform_1.translation -= inverse_mass_1 * normal_vec * separation;
+    transform_2.translation += inverse_mass_2 * normal_vec * separation;
 
     // Compute relative velocity along the collision normal
     let relative_velocity = velocity_2.0 - velocity_1.0;
@@ -316,10 +523,10 @@ fn separate_dynamic(
     }
 
     // Compute impulse scalar
-    let impulse = -(1.0 + RESTITUTION) * normal_velocity / (1.0 / mass_1 + 1.0 / mass_2);
+    let impulse = -(1.0 + RESTITUTION) * normal_velocity / weight;
 
     // Apply impulse to both velocities
-    velocity_1.0 -= (impulse / mass_1) * normal_vec;
-    velocity_2.0 += (impulse / mass_2) * normal_vec;
+    velocity_1.0 -= inverse_mass_1 * normal_vec * impulse;
+    velocity_2.0 += inverse_mass_2 * normal_vec * impulse;
 
 }
\ No newline at end of file
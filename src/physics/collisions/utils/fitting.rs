@@ -0,0 +1,127 @@
+use nalgebra::{Isometry3, Matrix3, Point3, Rotation3, SymmetricEigen, UnitQuaternion, Vector3};
+use parry3d::bounding_volume::{details::point_cloud_bounding_sphere, Aabb};
+use parry3d::shape::SharedShape;
+
+
+// Fits an axis aligned box to the vertices
+pub fn fit_aabb(verts: &[Point3<f32>]) -> SharedShape {
+    let aabb = Aabb::from_points(verts);
+    let half_extents = aabb.half_extents();
+
+    offset_shape(
+        SharedShape::cuboid(half_extents.x, half_extents.y, half_extents.z),
+        Isometry3::translation(aabb.center().x, aabb.center().y, aabb.center().z)
+    )
+}
+
+
+// Fits an oriented box to the vertices, aligned with their principal axes
+pub fn fit_obb(verts: &[Point3<f32>]) -> SharedShape {
+    let (centroid, axes) = principal_axes(verts);
+
+    // the extents of the vertices along each axis
+    let mut mins = Vector3::repeat(f32::MAX);
+    let mut maxs = Vector3::repeat(f32::MIN);
+
+    for vert in verts {
+        let local = axes.transpose() * (vert - centroid);
+        mins = mins.inf(&local);
+        maxs = maxs.sup(&local);
+    }
+
+    let half_extents = (maxs - mins) / 2.;
+    let center = centroid + axes * ((maxs + mins) / 2.);
+
+    let rotation = UnitQuaternion::from_rotation_matrix(&Rotation3::from_matrix_unchecked(axes));
+
+    offset_shape(
+        SharedShape::cuboid(half_extents.x, half_extents.y, half_extents.z),
+        Isometry3::from_parts(center.coords.into(), rotation)
+    )
+}
+
+
+// Fits a sphere enclosing all the vertices
+pub fn fit_bounding_sphere(verts: &[Point3<f32>]) -> SharedShape {
+    let (center, radius) = point_cloud_bounding_sphere(verts);
+
+    offset_shape(
+        SharedShape::ball(radius),
+        Isometry3::translation(center.x, center.y, center.z)
+    )
+}
+
+
+// Fits a capsule to the vertices, along their main principal axis
+pub fn fit_capsule(verts: &[Point3<f32>]) -> SharedShape {
+    let (centroid, axes) = principal_axes(verts);
+    let axis = axes.column(0).clone_owned();
+
+    let mut min_height = f32::MAX;
+    let mut max_height = f32::MIN;
+    let mut radius: f32 = 0.;
+
+    for vert in verts {
+        let offset = vert - centroid;
+        let height = offset.dot(&axis);
+
+        min_height = min_height.min(height);
+        max_height = max_height.max(height);
+
+        // the distance of the vertex from the axis
+        radius = radius.max((offset - axis * height).norm());
+    }
+
+    // the hemispheres cover the ends of the capsule
+    let half_height = ((max_height - min_height) / 2. - radius).max(0.);
+    let center = centroid + axis * ((max_height + min_height) / 2.);
+
+    SharedShape::capsule(
+        center - axis * half_height,
+        center + axis * half_height,
+        radius
+    )
+}
+
+
+// Computes the centroid of the vertices and their principal axes,
+// as the columns of a rotation matrix sorted from the longest to the shortest
+fn principal_axes(verts: &[Point3<f32>]) -> (Point3<f32>, Matrix3<f32>) {
+    let centroid = verts.iter()
+        .fold(Vector3::zeros(), |sum, vert| sum + vert.coords) / verts.len().max(1) as f32;
+
+    let covariance = verts.iter()
+        .fold(Matrix3::zeros(), |sum, vert| {
+            let offset = vert.coords - centroid;
+            sum + offset * offset.transpose()
+        });
+
+    let eigen = SymmetricEigen::new(covariance);
+
+    let mut order = [0, 1, 2];
+    order.sort_by(|a, b| eigen.eigenvalues[*b].total_cmp(&eigen.eigenvalues[*a]));
+
+    let mut axes = Matrix3::from_columns(&[
+        eigen.eigenvectors.column(order[0]).clone_owned(),
+        eigen.eigenvectors.column(order[1]).clone_owned(),
+        eigen.eigenvectors.column(order[2]).clone_owned(),
+    ]);
+
+    // keeps the axes right handed so they form a valid rotation
+    if axes.determinant() < 0. {
+        let flipped = -axes.column(2);
+        axes.set_column(2, &flipped);
+    }
+
+    (centroid.into(), axes)
+}
+
+
+// Places the shape at the isometry by wrapping it in a compound, if needed
+fn offset_shape(shape: SharedShape, isometry: Isometry3<f32>) -> SharedShape {
+    if isometry == Isometry3::identity() {
+        return shape;
+    }
+
+    SharedShape::compound(vec![(isometry, shape)])
+}
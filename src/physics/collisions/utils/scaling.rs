@@ -0,0 +1,69 @@
+use bevy::prelude::*;
+use nalgebra::{Isometry3, Vector3};
+use parry3d::either::Either;
+use parry3d::shape::{SharedShape, TypedShape};
+
+// the subdivisions used when a scaled shape has to be approximated by a convex polyhedron
+const SCALE_SUBDIVISIONS: u32 = 10;
+
+
+// Scales a shape along its local axes.
+// Negative scales are treated as positive, so the shapes are never mirrored.
+// Balls, capsules, cylinders and cones scaled non-uniformly are approximated by convex polyhedra.
+// The parts of compounds are moved by the scale but sized along their own axes, so a non-uniform scale
+// is only exact for the parts aligned with the compound's axes, the rotated ones would need a shear.
+// Returns None if the shape can't be scaled
+pub fn scale_shape(shape: &SharedShape, scale: Vec3) -> Option<SharedShape> {
+    if scale == Vec3::ONE {
+        return Some(shape.clone());
+    }
+
+    let offset_scale = Vector3::new(scale.x, scale.y, scale.z);
+    let scale = offset_scale.abs();
+
+    let scaled = match shape.as_typed_shape() {
+        TypedShape::Cuboid(cuboid) => SharedShape::new(cuboid.scaled(&scale)),
+        TypedShape::Ball(ball) => either_shape(ball.scaled(&scale, SCALE_SUBDIVISIONS)?),
+        TypedShape::Capsule(capsule) => either_shape(capsule.scaled(&scale, SCALE_SUBDIVISIONS)?),
+        TypedShape::Cylinder(cylinder) => either_shape(cylinder.scaled(&scale, SCALE_SUBDIVISIONS)?),
+        TypedShape::Cone(cone) => either_shape(cone.scaled(&scale, SCALE_SUBDIVISIONS)?),
+        TypedShape::ConvexPolyhedron(polyhedron) => SharedShape::new(polyhedron.clone().scaled(&scale)?),
+        TypedShape::TriMesh(trimesh) => SharedShape::new(trimesh.clone().scaled(&scale)),
+        TypedShape::HeightField(heightfield) => SharedShape::new(heightfield.clone().scaled(&scale)),
+        TypedShape::Triangle(triangle) => SharedShape::new(triangle.scaled(&scale)),
+        TypedShape::Segment(segment) => SharedShape::new(segment.scaled(&scale)),
+        TypedShape::Compound(compound) => {
+            let mut parts = Vec::new();
+
+            for (isometry, part) in compound.shapes() {
+                // the scale expressed along the part's axes, exact only if they are aligned with the compound's
+                let local_scale = (isometry.rotation.to_rotation_matrix().matrix().transpose() * scale).abs();
+
+                parts.push((
+                    Isometry3::from_parts(
+                        isometry.translation.vector.component_mul(&offset_scale).into(),
+                        isometry.rotation
+                    ),
+                    scale_shape(part, Vec3::new(local_scale.x, local_scale.y, local_scale.z))?
+                ));
+            }
+
+            SharedShape::compound(parts)
+        }
+        _ => return None,
+    };
+
+    Some(scaled)
+}
+
+
+fn either_shape<A, B>(shape: Either<A, B>) -> SharedShape
+where
+    A: parry3d::shape::Shape,
+    B: parry3d::shape::Shape,
+{
+    match shape {
+        Either::Left(shape) => SharedShape::new(shape),
+        Either::Right(shape) => SharedShape::new(shape),
+    }
+}
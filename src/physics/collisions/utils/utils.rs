@@ -1,6 +1,6 @@
 use nalgebra::{Isometry, Isometry3, Point3, Quaternion, Unit};
 use parry3d::bounding_volume::Aabb;
-use bevy::{prelude::*, render::mesh::{Indices, VertexAttributeValues}};
+use bevy::{prelude::*, render::mesh::{Indices, PrimitiveTopology, VertexAttributeValues}};
 
 
 // A struct containing a mesh's vertices and indices
@@ -9,14 +9,60 @@ pub struct VerticesIndices {
     pub indices: Vec<[u32; 3]>
 }
 
-// Extracts the vertices and indices of a mesh and returns them as a VerticesIndices instance
-pub fn extract_mesh_vertices_indices(mesh: &Mesh) -> Option<VerticesIndices> {
-    let vertices = mesh.attribute(Mesh::ATTRIBUTE_POSITION)?;
-    let indices = mesh.indices()?;
+/// The reasons a collider can't be generated
+#[derive(Debug, Clone, PartialEq)]
+pub enum ColliderGenerationError {
+    /// The mesh has no vertex positions
+    MissingPositions,
+    /// The vertex positions aren't Float32 or Float32x3
+    UnsupportedPositionFormat,
+    /// The mesh isn't made of triangles
+    UnsupportedTopology(PrimitiveTopology),
+    /// The mesh has no valid triangles, e.g. all of them are degenerate
+    NoTriangles,
+    /// A triangle refers to a vertex that doesn't exist
+    InvalidIndices,
+    /// The scene has no meshes a collider can be generated from, e.g. none of them matches the name filter
+    NoSceneMeshes,
+    /// The heights of a heightfield have rows of different lengths, or fewer than 2 rows or columns
+    InvalidHeights,
+    /// A trimesh or a heightfield can't be part of a compound
+    NestedComposite,
+    /// A compound has no parts
+    EmptyCompound,
+    /// The heightmap image format can't be read, or it's smaller than 2x2 pixels
+    UnsupportedImage,
+}
+
+impl std::fmt::Display for ColliderGenerationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ColliderGenerationError::MissingPositions => write!(f, "the mesh has no vertex positions"),
+            ColliderGenerationError::UnsupportedPositionFormat => write!(f, "the vertex positions must be Float32 or Float32x3"),
+            ColliderGenerationError::UnsupportedTopology(topology) => write!(f, "unsupported mesh topology {:?}", topology),
+            ColliderGenerationError::NoTriangles => write!(f, "the mesh has no valid triangles"),
+            ColliderGenerationError::InvalidIndices => write!(f, "a triangle index is out of the vertices"),
+            ColliderGenerationError::NoSceneMeshes => write!(f, "the scene has no usable meshes"),
+            ColliderGenerationError::InvalidHeights => write!(f, "the heightfield rows must have the same length, with at least 2 rows and columns"),
+            ColliderGenerationError::NestedComposite => write!(f, "trimeshes and heightfields can't be part of a compound"),
+            ColliderGenerationError::EmptyCompound => write!(f, "a compound needs at least one part"),
+            ColliderGenerationError::UnsupportedImage => write!(f, "unsupported heightmap image"),
+        }
+    }
+}
 
-    let vtx: Vec<_> = match vertices {
+// the minimum area of a triangle for it not to be considered degenerate
+const DEGENERATE_AREA: f32 = 1e-12;
+
+// Extracts the vertices and indices of a mesh and returns them as a VerticesIndices instance.
+// Non indexed meshes and triangle strips are triangulated, and degenerate triangles are removed
+pub fn extract_mesh_vertices_indices(mesh: &Mesh) -> Result<VerticesIndices, ColliderGenerationError> {
+    let vertices = mesh.attribute(Mesh::ATTRIBUTE_POSITION)
+        .ok_or(ColliderGenerationError::MissingPositions)?;
+
+    let vtx: Vec<nalgebra::Point3<f32>> = match vertices {
         VertexAttributeValues::Float32(vtx) => Some(
-            vtx.chunks(3)
+            vtx.chunks_exact(3)
                 .map(|v| [v[0], v[1], v[2]].into())
                 .collect(),
         ),
@@ -26,17 +72,45 @@ pub fn extract_mesh_vertices_indices(mesh: &Mesh) -> Option<VerticesIndices> {
                 .collect(),
         ),
         _ => None,
-    }?;
+    }.ok_or(ColliderGenerationError::UnsupportedPositionFormat)?;
 
-    let idx = match indices {
-        Indices::U16(idx) => idx
+    // the indices of the vertices, in order if the mesh isn't indexed
+    let idx: Vec<u32> = match mesh.indices() {
+        Some(Indices::U16(idx)) => idx.iter().map(|i| *i as u32).collect(),
+        Some(Indices::U32(idx)) => idx.clone(),
+        None => (0..vtx.len() as u32).collect(),
+    };
+
+    let triangles: Vec<[u32; 3]> = match mesh.primitive_topology() {
+        PrimitiveTopology::TriangleList => idx
             .chunks_exact(3)
-            .map(|i| [i[0] as u32, i[1] as u32, i[2] as u32])
+            .map(|i| [i[0], i[1], i[2]])
+            .collect(),
+        // every other triangle of a strip is flipped to keep the same winding
+        PrimitiveTopology::TriangleStrip => idx
+            .windows(3)
+            .enumerate()
+            .map(|(n, i)| if n % 2 == 0 { [i[0], i[1], i[2]] } else { [i[1], i[0], i[2]] })
             .collect(),
-        Indices::U32(idx) => idx.chunks_exact(3).map(|i| [i[0], i[1], i[2]]).collect(),
+        topology => return Err(ColliderGenerationError::UnsupportedTopology(topology)),
     };
 
-    Some(VerticesIndices {
+    // removes the triangles that are out of bounds or have no area
+    let idx: Vec<[u32; 3]> = triangles.into_iter()
+        .filter(|[a, b, c]| {
+            let (Some(a), Some(b), Some(c)) = (vtx.get(*a as usize), vtx.get(*b as usize), vtx.get(*c as usize)) else {
+                return false;
+            };
+
+            (b - a).cross(&(c - a)).norm_squared() > DEGENERATE_AREA
+        })
+        .collect();
+
+    if idx.is_empty() {
+        return Err(ColliderGenerationError::NoTriangles);
+    }
+
+    Ok(VerticesIndices {
         verts: vtx, 
         indices: idx
     })
@@ -95,4 +169,27 @@ pub fn transform_to_isometry(transform: Transform) -> Isometry<f32, Unit<Quatern
 
 
     isometry3d
-}
\ No newline at end of file
+}
+
+
+// converts a bevy global transform into a parry Isometry, the scale is applied to the shapes instead
+pub fn global_transform_to_isometry(global_transform: &GlobalTransform) -> Isometry<f32, Unit<Quaternion<f32>>, 3> {
+    let (_, rotation, translation) = global_transform.to_scale_rotation_translation();
+
+    transform_to_isometry(Transform::from_translation(translation).with_rotation(rotation))
+}
+
+
+// moves a global transform in world space
+pub fn translate_global_transform(global_transform: &mut GlobalTransform, translation: Vec3) {
+    *global_transform = GlobalTransform::from_translation(translation) * *global_transform;
+}
+
+
+// rotates a global transform around a point in world space
+pub fn rotate_global_transform(global_transform: &mut GlobalTransform, rotation: Quat, pivot: Vec3) {
+    *global_transform = GlobalTransform::from_translation(pivot)
+        * GlobalTransform::from_rotation(rotation)
+        * GlobalTransform::from_translation(-pivot)
+        * *global_transform;
+}
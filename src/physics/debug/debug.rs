@@ -0,0 +1,316 @@
+use bevy::prelude::*;
+use nalgebra::{Isometry3, Point3};
+use parry3d::bounding_volume::Aabb;
+use parry3d::shape::{Shape, TypedShape};
+
+use super::collisions::{
+    collider::{ColliderAabb, ScaledShape}, 
+    octree::Octree, 
+    utils::global_transform_to_isometry, 
+    Contacts, 
+    MAX_ENTITIES
+};
+use super::{RigidBody, Velocity};
+
+pub struct PhysicsDebugPlugin;
+
+impl Plugin for PhysicsDebugPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_gizmo_group::<PhysicsGizmos>()
+            .init_resource::<PhysicsDebugConfig>()
+            .add_systems(Update, (
+                toggle_debug_categories,
+                (
+                    draw_colliders,
+                    draw_aabbs,
+                    draw_octree,
+                    draw_chunk_labels,
+                    draw_contacts,
+                    draw_velocities,
+                ),
+            ).chain());
+    }
+}
+
+
+/// The gizmo group used to draw the physics debug shapes
+#[derive(Default, Reflect, GizmoConfigGroup)]
+pub struct PhysicsGizmos;
+
+/// Which categories of the physics debug rendering are drawn.
+/// Each category can be toggled at runtime with the function keys
+#[derive(Resource)]
+pub struct PhysicsDebugConfig {
+    pub colliders: bool,    // F1
+    pub aabbs: bool,        // F2
+    pub octree: bool,       // F3
+    pub contacts: bool,     // F4
+    pub velocities: bool,   // F5
+}
+
+impl Default for PhysicsDebugConfig {
+    fn default() -> Self {
+        PhysicsDebugConfig {
+            colliders: true,
+            aabbs: false,
+            octree: false,
+            contacts: true,
+            velocities: false,
+        }
+    }
+}
+
+const COLLIDER_COLOR: Color = Color::srgb(0.2, 1., 0.2);
+const AABB_COLOR: Color = Color::srgb(0.2, 0.5, 1.);
+const CONTACT_COLOR: Color = Color::srgb(1., 0.2, 0.2);
+const VELOCITY_COLOR: Color = Color::srgb(1., 1., 0.2);
+
+// the length of the drawn contact normals
+const NORMAL_LENGTH: f32 = 0.5;
+
+// the velocity vectors are scaled by this amount before being drawn
+const VELOCITY_SCALE: f32 = 0.2;
+
+
+fn toggle_debug_categories(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut config: ResMut<PhysicsDebugConfig>,
+) {
+    if keys.just_pressed(KeyCode::F1) { config.colliders = !config.colliders }
+    if keys.just_pressed(KeyCode::F2) { config.aabbs = !config.aabbs }
+    if keys.just_pressed(KeyCode::F3) { config.octree = !config.octree }
+    if keys.just_pressed(KeyCode::F4) { config.contacts = !config.contacts }
+    if keys.just_pressed(KeyCode::F5) { config.velocities = !config.velocities }
+}
+
+
+/// Draws the wireframe of every collider
+fn draw_colliders(
+    config: Res<PhysicsDebugConfig>,
+    query: Query<(&ScaledShape, &GlobalTransform), With<RigidBody>>,
+    mut gizmos: Gizmos<PhysicsGizmos>,
+) {
+    if !config.colliders { return }
+
+    for (scaled_shape, global_transform) in query.iter() {
+        draw_shape(&mut gizmos, &*scaled_shape.shape, &global_transform_to_isometry(global_transform), COLLIDER_COLOR);
+    }
+}
+
+
+/// Draws the world space aabb of every collider
+fn draw_aabbs(
+    config: Res<PhysicsDebugConfig>,
+    query: Query<&ColliderAabb, With<RigidBody>>,
+    mut gizmos: Gizmos<PhysicsGizmos>,
+) {
+    if !config.aabbs { return }
+
+    for aabb in query.iter() {
+        draw_aabb(&mut gizmos, &aabb.0, AABB_COLOR);
+    }
+}
+
+
+/// Draws the octree leaves, from green (empty) to red (full)
+fn draw_octree(
+    config: Res<PhysicsDebugConfig>,
+    octree: Res<Octree>,
+    mut gizmos: Gizmos<PhysicsGizmos>,
+) {
+    if !config.octree { return }
+
+    for chunk in octree.leaves() {
+        let occupancy = (chunk.occupancy as f32 / MAX_ENTITIES as f32).min(1.);
+        draw_aabb(&mut gizmos, &chunk.bounding_box, Color::srgb(occupancy, 1. - occupancy, 0.));
+    }
+}
+
+
+/// Marks the ui labels showing the octree chunk numbers
+#[derive(Component)]
+struct ChunkLabel;
+
+/// Shows the chunk_num of every occupied octree leaf at its center
+fn draw_chunk_labels(
+    config: Res<PhysicsDebugConfig>,
+    octree: Res<Octree>,
+    camera_q: Query<(&Camera, &GlobalTransform)>,
+    mut label_q: Query<(&mut Text, &mut Node, &mut Visibility), With<ChunkLabel>>,
+    mut commands: Commands,
+) {
+    let mut labels = label_q.iter_mut();
+
+    if config.octree {
+        if let Ok((camera, camera_transform)) = camera_q.get_single() {
+
+            for chunk in octree.leaves().into_iter().filter(|chunk| chunk.occupancy > 0) {
+                let center = point_to_vec3(chunk.bounding_box.center());
+
+                let Ok(position) = camera.world_to_viewport(camera_transform, center) else {
+                    continue;
+                };
+
+                // reuses the existing labels before spawning new ones
+                match labels.next() {
+                    Some((mut text, mut node, mut visibility)) => {
+                        text.0 = chunk.chunk_num.to_string();
+                        node.left = Val::Px(position.x);
+                        node.top = Val::Px(position.y);
+                        *visibility = Visibility::Inherited;
+                    }
+                    None => {
+                        commands.spawn((
+                            Text::new(chunk.chunk_num.to_string()),
+                            TextFont {
+                                font_size: 12.,
+                                ..default()
+                            },
+                            Node {
+                                position_type: PositionType::Absolute,
+                                left: Val::Px(position.x),
+                                top: Val::Px(position.y),
+                                ..default()
+                            },
+                            ChunkLabel,
+                        ));
+                    }
+                }
+            }
+        }
+    }
+
+    // hides the labels that weren't used this frame
+    for (_, _, mut visibility) in labels {
+        *visibility = Visibility::Hidden;
+    }
+}
+
+
+/// Draws the contact points and normals generated by the narrow phase
+fn draw_contacts(
+    config: Res<PhysicsDebugConfig>,
+    contacts: Res<Contacts>,
+    mut gizmos: Gizmos<PhysicsGizmos>,
+) {
+    if !config.contacts { return }
+
+    for contact_pair in contacts.0.iter() {
+        let contact = contact_pair.contact;
+
+        for (point, normal) in [
+            (contact.point1, contact.normal1),
+            (contact.point2, contact.normal2),
+        ] {
+            let point = point_to_vec3(point);
+            let normal = Vec3::new(normal.x, normal.y, normal.z);
+
+            gizmos.sphere(Isometry3d::from_translation(point), 0.05, CONTACT_COLOR);
+            gizmos.arrow(point, point + normal * NORMAL_LENGTH, CONTACT_COLOR);
+        }
+    }
+}
+
+
+/// Draws the velocity vector of every body
+fn draw_velocities(
+    config: Res<PhysicsDebugConfig>,
+    query: Query<(&GlobalTransform, &Velocity)>,
+    mut gizmos: Gizmos<PhysicsGizmos>,
+) {
+    if !config.velocities { return }
+
+    for (global_transform, velocity) in query.iter() {
+        if velocity.0 == Vec3::ZERO { continue }
+
+        gizmos.arrow(
+            global_transform.translation(), 
+            global_transform.translation() + velocity.0 * VELOCITY_SCALE, 
+            VELOCITY_COLOR
+        );
+    }
+}
+
+
+// Draws the wireframe of a parry shape placed at the given isometry
+fn draw_shape(
+    gizmos: &mut Gizmos<PhysicsGizmos>,
+    shape: &dyn Shape,
+    isometry: &Isometry3<f32>,
+    color: Color,
+) {
+    let translation = Vec3::new(isometry.translation.x, isometry.translation.y, isometry.translation.z);
+    let rotation = Quat::from_xyzw(
+        isometry.rotation.i, 
+        isometry.rotation.j, 
+        isometry.rotation.k, 
+        isometry.rotation.w
+    );
+
+    match shape.as_typed_shape() {
+        TypedShape::Cuboid(cuboid) => {
+            let half_extents = cuboid.half_extents;
+
+            gizmos.cuboid(
+                Transform::from_translation(translation)
+                    .with_rotation(rotation)
+                    .with_scale(Vec3::new(half_extents.x, half_extents.y, half_extents.z) * 2.),
+                color
+            );
+        }
+        TypedShape::Ball(ball) => {
+            gizmos.sphere(Isometry3d::new(translation, rotation), ball.radius, color);
+        }
+        TypedShape::TriMesh(trimesh) => {
+            for triangle in trimesh.triangles() {
+                let a = point_to_vec3(isometry * triangle.a);
+                let b = point_to_vec3(isometry * triangle.b);
+                let c = point_to_vec3(isometry * triangle.c);
+
+                gizmos.linestrip([a, b, c, a], color);
+            }
+        }
+        TypedShape::ConvexPolyhedron(polyhedron) => {
+            let points = polyhedron.points();
+
+            for edge in polyhedron.edges() {
+                gizmos.line(
+                    point_to_vec3(isometry * points[edge.vertices.x as usize]),
+                    point_to_vec3(isometry * points[edge.vertices.y as usize]),
+                    color
+                );
+            }
+        }
+        TypedShape::Compound(compound) => {
+            for (sub_isometry, sub_shape) in compound.shapes() {
+                draw_shape(gizmos, &**sub_shape, &(isometry * sub_isometry), color);
+            }
+        }
+        // every other shape is approximated by its aabb
+        _ => {
+            draw_aabb(gizmos, &shape.compute_aabb(isometry), color);
+        }
+    }
+}
+
+
+// Draws an axis aligned bounding box
+fn draw_aabb(
+    gizmos: &mut Gizmos<PhysicsGizmos>,
+    aabb: &Aabb,
+    color: Color,
+) {
+    let extents = aabb.extents();
+
+    gizmos.cuboid(
+        Transform::from_translation(point_to_vec3(aabb.center()))
+            .with_scale(Vec3::new(extents.x, extents.y, extents.z)),
+        color
+    );
+}
+
+
+fn point_to_vec3(point: Point3<f32>) -> Vec3 {
+    Vec3::new(point.x, point.y, point.z)
+}
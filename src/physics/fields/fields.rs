@@ -0,0 +1,161 @@
+use bevy::prelude::*;
+
+use super::character::CharacterController;
+use super::collisions::collider::{ColliderAabb, CollisionLayers, ScaledShape, Sensor};
+use super::collisions::octree::Octree;
+use super::collisions::utils::global_transform_to_isometry;
+use super::mass::MassProperties;
+use super::{RigidBody, Velocity, DELTA};
+
+
+/// Pushes the dynamic bodies whose center of mass is inside the Collider of a RigidBody::Static,
+/// and pulls the characters inside it, turning them so they stand against the pull.
+/// The strength is an acceleration, so every body moves the same regardless of its mass,
+/// except for wind which pushes with a force, so lighter bodies are blown away faster.
+/// Only the bodies whose CollisionLayers interact with the field's are affected
+#[derive(Component, Clone, Copy)]
+#[require(Sensor)]
+pub struct ForceField {
+    pub kind: ForceFieldKind,
+    pub strength: f32,
+    pub falloff: Falloff,
+}
+
+/// How a ForceField pushes the bodies, the directions and axes are in the field's local space
+#[derive(Clone, Copy)]
+pub enum ForceFieldKind {
+    /// Pulls the bodies towards the field's center, like the gravity of a small planet
+    Attractor,
+    /// Pushes the bodies away from the field's center
+    Repulsor,
+    /// Pushes the bodies along the direction
+    Wind { direction: Vec3 },
+    /// Spins the bodies around the axis through the field's center
+    Vortex { axis: Vec3 },
+}
+
+/// How the strength of a ForceField decreases with the distance from its center
+#[derive(Clone, Copy, Default)]
+pub enum Falloff {
+    /// The same strength in the whole field
+    #[default]
+    Constant,
+    /// Fades from the full strength at the center to zero at the distance
+    Linear { distance: f32 },
+    /// Full strength within the distance, then decreases with the square of the distance
+    InverseSquare { distance: f32 },
+}
+
+impl Falloff {
+    /// The fraction of the strength at the distance from the center
+    pub fn factor(&self, distance: f32) -> f32 {
+        match *self {
+            Falloff::Constant => 1.,
+            Falloff::Linear { distance: max_distance } => (1. - distance / max_distance.max(f32::EPSILON)).max(0.),
+            Falloff::InverseSquare { distance: min_distance } => {
+                if distance <= min_distance {
+                    1.
+                }
+                else {
+                    (min_distance / distance).powi(2)
+                }
+            }
+        }
+    }
+}
+
+impl ForceField {
+    pub fn new(kind: ForceFieldKind, strength: f32) -> Self {
+        ForceField {
+            kind,
+            strength,
+            falloff: Falloff::Constant,
+        }
+    }
+
+    pub fn with_falloff(mut self, falloff: Falloff) -> Self {
+        self.falloff = falloff;
+        self
+    }
+}
+
+
+/// Applies the force fields to the dynamic bodies inside them.
+/// The characters inside them get the fields' acceleration added to their gravity instead
+pub fn apply_force_fields(
+    field_q: Query<(&ForceField, &GlobalTransform, &ScaledShape, &ColliderAabb, Option<&CollisionLayers>)>,
+    mut body_q: Query<(
+        &RigidBody,
+        &GlobalTransform,
+        &MassProperties,
+        &mut Velocity,
+        Option<&CollisionLayers>
+    ), (Without<ForceField>, Without<CharacterController>)>,
+    mut character_q: Query<(&GlobalTransform, Option<&MassProperties>, &mut CharacterController, Option<&CollisionLayers>)>,
+    octree: Res<Octree>,
+) {
+    for (_, _, mut controller, _) in character_q.iter_mut() {
+        controller.field_acceleration = Vec3::ZERO;
+    }
+
+    for (field, field_transform, field_shape, field_aabb, field_layers) in field_q.iter() {
+        let field_isometry = global_transform_to_isometry(field_transform);
+        let field_layers = field_layers.copied().unwrap_or_default();
+
+        let aabb_center = field_aabb.0.center();
+        let radius = field_aabb.0.half_extents().norm();
+
+        let inside = |point: Vec3, layers: Option<&CollisionLayers>| {
+            field_layers.interacts_with(&layers.copied().unwrap_or_default())
+                && field_shape.shape.contains_point(&field_isometry, &[point.x, point.y, point.z].into())
+        };
+
+        for entity in octree.entities_within_radius(Vec3::new(aabb_center.x, aabb_center.y, aabb_center.z), radius) {
+            if let Ok((global_transform, mass_properties, mut controller, layers)) = character_q.get_mut(entity) {
+                let position = global_transform.translation();
+
+                if inside(position, layers) {
+                    let inverse_mass = mass_properties.map_or(1., |mass_properties| mass_properties.inverse_mass());
+                    controller.field_acceleration += field_acceleration(field, field_transform, position, inverse_mass);
+                }
+
+                continue;
+            }
+
+            let Ok((rigid_body, global_transform, mass_properties, mut velocity, layers)) = body_q.get_mut(entity) else {
+                continue;
+            };
+
+            let RigidBody::Dynamic = rigid_body else {
+                continue;
+            };
+
+            let center_of_mass = mass_properties.world_center_of_mass(global_transform);
+
+            if !inside(center_of_mass, layers) {
+                continue;
+            }
+
+            velocity.0 += field_acceleration(field, field_transform, center_of_mass, mass_properties.inverse_mass()) * DELTA;
+        }
+    }
+}
+
+
+// The acceleration of a body at the point, inside the field
+fn field_acceleration(field: &ForceField, field_transform: &GlobalTransform, point: Vec3, inverse_mass: f32) -> Vec3 {
+    let (_, field_rotation, field_center) = field_transform.to_scale_rotation_translation();
+
+    let offset = point - field_center;
+    let strength = field.strength * field.falloff.factor(offset.length());
+
+    match field.kind {
+        ForceFieldKind::Attractor => -offset.normalize_or_zero() * strength,
+        ForceFieldKind::Repulsor => offset.normalize_or_zero() * strength,
+        ForceFieldKind::Wind { direction } => (field_rotation * direction).normalize_or_zero() * strength * inverse_mass,
+        ForceFieldKind::Vortex { axis } => {
+            let axis = (field_rotation * axis).normalize_or_zero();
+            axis.cross(offset).normalize_or_zero() * strength
+        }
+    }
+}
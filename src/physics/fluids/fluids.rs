@@ -0,0 +1,132 @@
+use bevy::prelude::*;
+use nalgebra::{Point3, Vector3};
+use parry3d::bounding_volume::Aabb;
+
+use super::collisions::collider::{ColliderAabb, ScaledShape, Sensor};
+use super::collisions::octree::Octree;
+use super::collisions::utils::global_transform_to_isometry;
+use super::mass::MassProperties;
+use super::{AngularVelocity, RigidBody, Velocity, DELTA, GRAVITY};
+
+// the number of points sampled along each axis of a body's aabb to find its submerged part
+const BUOYANCY_SAMPLES: usize = 4;
+
+
+/// A volume of fluid, like water or mud, filling the Collider of a RigidBody::Static.
+/// The dynamic bodies inside it float or sink depending on their mass and are slowed down by its drag
+#[derive(Component, Clone, Copy)]
+#[require(Sensor)]
+pub struct FluidVolume {
+    pub density: f32,
+    /// How fast the fluid slows down the bodies fully inside it, per second
+    pub linear_drag: f32,
+    /// How fast the fluid slows down the rotation of the bodies fully inside it, per second
+    pub angular_drag: f32,
+}
+
+impl Default for FluidVolume {
+    fn default() -> Self {
+        FluidVolume {
+            density: 1.,
+            linear_drag: 1.,
+            angular_drag: 1.,
+        }
+    }
+}
+
+
+/// Applies the buoyancy and drag of the fluid volumes to the dynamic bodies inside them.
+/// The buoyancy is proportional to the submerged part of each body, sampled from points of its aabb
+pub fn apply_buoyancy(
+    fluid_q: Query<(&FluidVolume, &GlobalTransform, &ScaledShape, &ColliderAabb)>,
+    mut body_q: Query<(
+        &RigidBody,
+        &GlobalTransform,
+        &ScaledShape,
+        &ColliderAabb,
+        &MassProperties,
+        &mut Velocity,
+        Option<&mut AngularVelocity>
+    ), Without<FluidVolume>>,
+    octree: Res<Octree>,
+) {
+    for (fluid, fluid_transform, fluid_shape, fluid_aabb) in fluid_q.iter() {
+        let fluid_isometry = global_transform_to_isometry(fluid_transform);
+
+        let center = fluid_aabb.0.center();
+        let radius = fluid_aabb.0.half_extents().norm();
+
+        for entity in octree.entities_within_radius(Vec3::new(center.x, center.y, center.z), radius) {
+            let Ok((rigid_body, global_transform, shape, aabb, mass_properties, mut velocity, angular_velocity)) = body_q.get_mut(entity) else {
+                continue;
+            };
+
+            let RigidBody::Dynamic = rigid_body else {
+                continue;
+            };
+
+            let body_isometry = global_transform_to_isometry(global_transform);
+
+            // the points of the body's aabb inside the body, and the ones of them inside the fluid
+            let mut body_points = 0;
+            let mut submerged_points = 0;
+            let mut submerged_center = Vec3::ZERO;
+
+            for point in aabb_samples(&aabb.0) {
+                if !shape.shape.contains_point(&body_isometry, &point) {
+                    continue;
+                }
+
+                body_points += 1;
+
+                if fluid_shape.shape.contains_point(&fluid_isometry, &point) {
+                    submerged_points += 1;
+                    submerged_center += Vec3::new(point.x, point.y, point.z);
+                }
+            }
+
+            if submerged_points == 0 {
+                continue;
+            }
+
+            let submerged = submerged_points as f32 / body_points as f32;
+            submerged_center /= submerged_points as f32;
+
+            // the volume of the body, its aabb's if the shape has no volume
+            let volume = match shape.shape.mass_properties(1.).mass() {
+                volume if volume > 0. => volume,
+                _ => aabb.0.volume(),
+            };
+
+            // pushes the body up as much as gravity pulls down the fluid it displaces
+            let buoyancy = GRAVITY * DELTA * fluid.density * volume * submerged * mass_properties.inverse_mass();
+            velocity.0.y += buoyancy;
+
+            velocity.0 *= (-fluid.linear_drag * submerged * DELTA).exp();
+
+            if let Some(mut angular_velocity) = angular_velocity {
+                // the buoyancy pushes at the center of the submerged part, so floating bodies turn upright
+                let (_, rotation, _) = global_transform.to_scale_rotation_translation();
+                let arm = submerged_center - mass_properties.world_center_of_mass(global_transform);
+                let impulse = Vec3::Y * buoyancy * mass_properties.mass;
+
+                angular_velocity.0 += mass_properties.world_inverse_inertia(rotation) * arm.cross(impulse);
+                angular_velocity.0 *= (-fluid.angular_drag * submerged * DELTA).exp();
+            }
+        }
+    }
+}
+
+
+// The points at the centers of a grid of cells dividing the aabb
+fn aabb_samples(aabb: &Aabb) -> impl Iterator<Item = Point3<f32>> + '_ {
+    let cell = aabb.extents() / BUOYANCY_SAMPLES as f32;
+
+    (0..BUOYANCY_SAMPLES.pow(3)).map(move |index| {
+        let x = index % BUOYANCY_SAMPLES;
+        let y = index / BUOYANCY_SAMPLES % BUOYANCY_SAMPLES;
+        let z = index / (BUOYANCY_SAMPLES * BUOYANCY_SAMPLES);
+
+        aabb.mins + cell.component_mul(&Vector3::new(x as f32 + 0.5, y as f32 + 0.5, z as f32 + 0.5))
+    })
+}
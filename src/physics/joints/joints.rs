@@ -0,0 +1,578 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+
+use super::collisions::utils::{rotate_global_transform, translate_global_transform};
+use super::mass::MassProperties;
+use super::{AngularVelocity, LockedAxes, RigidBody, Velocity, DELTA};
+
+// the number of times the joints are solved each step, more iterations make chains stiffer
+const JOINT_ITERATIONS: usize = 8;
+
+// the errors smaller than this are left uncorrected
+const JOINT_TOLERANCE: f32 = 1e-5;
+
+
+/// Connects two bodies.
+/// The anchors and axes are in the local space of each body.
+/// Bodies without an AngularVelocity can't be rotated by the joint
+#[derive(Component, Clone)]
+pub struct Joint {
+    pub body_1: Entity,
+    pub body_2: Entity,
+    pub kind: JointKind,
+    pub anchor_1: Vec3,
+    pub anchor_2: Vec3,
+    /// The axis of revolute and prismatic joints and of the spherical joint's swing limit
+    pub axis_1: Vec3,
+    pub axis_2: Vec3,
+    pub limits: Option<JointLimits>,
+    pub motor: Option<JointMotor>,
+    /// If the connected bodies can collide with each other
+    pub collide_connected: bool,
+}
+
+/// How a Joint constrains its bodies
+#[derive(Clone, Copy, PartialEq)]
+pub enum JointKind {
+    /// Keeps the anchors together and the relative rotation of the bodies
+    Fixed,
+    /// A hinge, the bodies can only rotate around the axis
+    Revolute,
+    /// A slider, the bodies can only move along the axis
+    Prismatic,
+    /// A ball and socket, the bodies can rotate freely around the anchors
+    Spherical,
+    /// Keeps the anchors at a distance
+    Distance { rest_length: f32 },
+}
+
+/// The range a Joint can move in.
+/// Angles in radians for revolute joints, the swing angle for spherical joints (only max is used),
+/// the offset along the axis for prismatic joints and the distance for distance joints
+#[derive(Clone, Copy)]
+pub struct JointLimits {
+    pub min: f32,
+    pub max: f32,
+}
+
+/// Drives a revolute or prismatic Joint at a target velocity
+#[derive(Clone, Copy)]
+pub struct JointMotor {
+    /// In radians per second for revolute joints and units per second for prismatic joints
+    pub target_velocity: f32,
+    /// The maximum torque or force the motor can apply
+    pub max_force: f32,
+}
+
+impl Joint {
+    fn new(body_1: Entity, body_2: Entity, kind: JointKind) -> Self {
+        Joint {
+            body_1,
+            body_2,
+            kind,
+            anchor_1: Vec3::ZERO,
+            anchor_2: Vec3::ZERO,
+            axis_1: Vec3::Y,
+            axis_2: Vec3::Y,
+            limits: None,
+            motor: None,
+            collide_connected: false,
+        }
+    }
+
+    pub fn fixed(body_1: Entity, body_2: Entity) -> Self {
+        Joint::new(body_1, body_2, JointKind::Fixed)
+    }
+
+    pub fn revolute(body_1: Entity, body_2: Entity, axis: Vec3) -> Self {
+        Joint::new(body_1, body_2, JointKind::Revolute).with_axes(axis, axis)
+    }
+
+    pub fn prismatic(body_1: Entity, body_2: Entity, axis: Vec3) -> Self {
+        Joint::new(body_1, body_2, JointKind::Prismatic).with_axes(axis, axis)
+    }
+
+    pub fn spherical(body_1: Entity, body_2: Entity) -> Self {
+        Joint::new(body_1, body_2, JointKind::Spherical)
+    }
+
+    pub fn distance(body_1: Entity, body_2: Entity, rest_length: f32) -> Self {
+        Joint::new(body_1, body_2, JointKind::Distance { rest_length })
+    }
+
+    pub fn with_anchors(mut self, anchor_1: Vec3, anchor_2: Vec3) -> Self {
+        self.anchor_1 = anchor_1;
+        self.anchor_2 = anchor_2;
+        self
+    }
+
+    pub fn with_axes(mut self, axis_1: Vec3, axis_2: Vec3) -> Self {
+        self.axis_1 = axis_1.normalize_or(Vec3::Y);
+        self.axis_2 = axis_2.normalize_or(Vec3::Y);
+        self
+    }
+
+    pub fn with_limits(mut self, min: f32, max: f32) -> Self {
+        self.limits = Some(JointLimits { min, max });
+        self
+    }
+
+    pub fn with_motor(mut self, target_velocity: f32, max_force: f32) -> Self {
+        self.motor = Some(JointMotor { target_velocity, max_force });
+        self
+    }
+
+    pub fn with_collide_connected(mut self, collide_connected: bool) -> Self {
+        self.collide_connected = collide_connected;
+        self
+    }
+}
+
+/// The relative rotation of the bodies when the joint was created,
+/// used as the rest pose of fixed joints and the zero angle of revolute joints
+#[derive(Component, Clone, Copy)]
+pub struct JointRestRotation(pub Quat);
+
+
+// The state of a body while the joints are solved
+struct JointBody {
+    translation: Vec3,
+    rotation: Quat,
+    scale: Vec3,
+    local_center_of_mass: Vec3,
+    /// The inverse mass along each axis, zero along the locked ones
+    inverse_mass: Vec3,
+    /// The inverse inertia, zero around the locked axes
+    inverse_inertia: Mat3,
+}
+
+impl JointBody {
+    fn new(
+        global_transform: &GlobalTransform,
+        rigid_body: &RigidBody,
+        mass_properties: Option<&MassProperties>,
+        can_move: bool,
+        can_rotate: bool,
+        locked_axes: Option<&LockedAxes>,
+    ) -> Self {
+        let (scale, rotation, translation) = global_transform.to_scale_rotation_translation();
+        let dynamic = matches!(rigid_body, RigidBody::Dynamic);
+
+        let (local_center_of_mass, mut inverse_mass, mut inverse_inertia) = match mass_properties {
+            Some(mass_properties) => (
+                mass_properties.local_center_of_mass,
+                if dynamic && can_move { Vec3::splat(mass_properties.inverse_mass()) } else { Vec3::ZERO },
+                if dynamic && can_rotate { mass_properties.world_inverse_inertia(rotation) } else { Mat3::ZERO },
+            ),
+            None => (Vec3::ZERO, Vec3::ZERO, Mat3::ZERO),
+        };
+
+        // the joints can't move or rotate the body along its locked axes
+        if let Some(locked_axes) = locked_axes {
+            let free_rotation = Mat3::from_diagonal(locked_axes.mask_rotation(Vec3::ONE));
+
+            inverse_mass = locked_axes.mask_translation(inverse_mass);
+            inverse_inertia = free_rotation * inverse_inertia * free_rotation;
+        }
+
+        JointBody {
+            translation,
+            rotation,
+            scale,
+            local_center_of_mass,
+            inverse_mass,
+            inverse_inertia,
+        }
+    }
+
+    fn center_of_mass(&self) -> Vec3 {
+        self.translation + self.rotation * self.local_center_of_mass
+    }
+
+    fn world_anchor(&self, anchor: Vec3) -> Vec3 {
+        self.translation + self.rotation * (self.scale * anchor)
+    }
+
+    // how hard it is to move the point at offset from the center of mass along the direction
+    fn positional_weight(&self, offset: Vec3, direction: Vec3) -> f32 {
+        let arm = offset.cross(direction);
+
+        (direction * direction).dot(self.inverse_mass) + arm.dot(self.inverse_inertia * arm)
+    }
+
+    fn angular_weight(&self, axis: Vec3) -> f32 {
+        axis.dot(self.inverse_inertia * axis)
+    }
+
+    // rotates the body around its center of mass by a rotation vector
+    fn rotate(&mut self, rotation: Vec3) {
+        if rotation == Vec3::ZERO {
+            return;
+        }
+
+        let center_of_mass = self.center_of_mass();
+
+        let delta = Quat::from_xyzw(rotation.x, rotation.y, rotation.z, 0.) * self.rotation * 0.5;
+        self.rotation = (self.rotation + delta).normalize();
+
+        self.translation = center_of_mass - self.rotation * self.local_center_of_mass;
+    }
+}
+
+
+// Moves the bodies to remove the offset between the anchors, offset being anchor_2 - anchor_1
+fn apply_positional_correction(
+    body_1: &mut JointBody,
+    body_2: &mut JointBody,
+    offset: Vec3,
+    anchor_1: Vec3,
+    anchor_2: Vec3,
+) {
+    let error = offset.length();
+
+    if error <= JOINT_TOLERANCE {
+        return;
+    }
+
+    let direction = offset / error;
+    let arm_1 = anchor_1 - body_1.center_of_mass();
+    let arm_2 = anchor_2 - body_2.center_of_mass();
+
+    let weight = body_1.positional_weight(arm_1, direction) + body_2.positional_weight(arm_2, direction);
+
+    if weight <= 0. {
+        return;
+    }
+
+    let impulse = offset / weight;
+
+    body_1.translation += impulse * body_1.inverse_mass;
+    body_1.rotate(body_1.inverse_inertia * arm_1.cross(impulse));
+
+    body_2.translation -= impulse * body_2.inverse_mass;
+    body_2.rotate(-(body_2.inverse_inertia * arm_2.cross(impulse)));
+}
+
+
+// Rotates the bodies towards each other by the rotation vector, body 1 forwards and body 2 backwards
+fn apply_angular_correction(body_1: &mut JointBody, body_2: &mut JointBody, rotation: Vec3) {
+    let error = rotation.length();
+
+    if error <= JOINT_TOLERANCE {
+        return;
+    }
+
+    let axis = rotation / error;
+    let weight = body_1.angular_weight(axis) + body_2.angular_weight(axis);
+
+    if weight <= 0. {
+        return;
+    }
+
+    let impulse = rotation / weight;
+
+    body_1.rotate(body_1.inverse_inertia * impulse);
+    body_2.rotate(-(body_2.inverse_inertia * impulse));
+}
+
+
+// The rotation vector turning body 2 back to its rest rotation relative to body 1
+fn rest_rotation_error(body_1: &JointBody, body_2: &JointBody, rest_rotation: Quat) -> Vec3 {
+    let mut error = body_2.rotation * rest_rotation.inverse() * body_1.rotation.inverse();
+
+    // the shortest of the two equivalent rotations
+    if error.w < 0. {
+        error = -error;
+    }
+
+    error.xyz() * 2.
+}
+
+
+// The rotation of body 2 around the hinge axis relative to its rest rotation
+fn revolute_angle(body_1: &JointBody, body_2: &JointBody, joint: &Joint, rest_rotation: Quat) -> f32 {
+    let axis = body_1.rotation * joint.axis_1;
+    let reference = joint.axis_1.any_orthonormal_vector();
+
+    let reference_1 = body_1.rotation * reference;
+    let reference_2 = body_2.rotation * rest_rotation.inverse() * reference;
+
+    reference_1.cross(reference_2).dot(axis).atan2(reference_1.dot(reference_2))
+}
+
+
+// Projects the bodies so they satisfy the joint
+fn solve_joint(body_1: &mut JointBody, body_2: &mut JointBody, joint: &Joint, rest_rotation: Quat) {
+    match joint.kind {
+        JointKind::Fixed => {
+            apply_angular_correction(body_1, body_2, rest_rotation_error(body_1, body_2, rest_rotation));
+        }
+        JointKind::Revolute => {
+            let axis_1 = body_1.rotation * joint.axis_1;
+            let axis_2 = body_2.rotation * joint.axis_2;
+
+            // aligns the hinge axes
+            apply_angular_correction(body_1, body_2, axis_1.cross(axis_2));
+
+            if let Some(limits) = joint.limits {
+                let angle = revolute_angle(body_1, body_2, joint, rest_rotation);
+                let clamped = angle.clamp(limits.min, limits.max);
+
+                apply_angular_correction(body_1, body_2, body_1.rotation * joint.axis_1 * (angle - clamped));
+            }
+        }
+        JointKind::Prismatic => {
+            apply_angular_correction(body_1, body_2, rest_rotation_error(body_1, body_2, rest_rotation));
+        }
+        JointKind::Spherical => {
+            if let Some(limits) = joint.limits {
+                let axis_1 = body_1.rotation * joint.axis_1;
+                let axis_2 = body_2.rotation * joint.axis_2;
+                let swing = axis_1.angle_between(axis_2);
+
+                if swing > limits.max {
+                    let swing_axis = axis_1.cross(axis_2).normalize_or_zero();
+
+                    apply_angular_correction(body_1, body_2, swing_axis * (swing - limits.max));
+                }
+            }
+        }
+        JointKind::Distance { .. } => {}
+    }
+
+    let anchor_1 = body_1.world_anchor(joint.anchor_1);
+    let anchor_2 = body_2.world_anchor(joint.anchor_2);
+    let offset = anchor_2 - anchor_1;
+
+    match joint.kind {
+        JointKind::Fixed | JointKind::Revolute | JointKind::Spherical => {
+            apply_positional_correction(body_1, body_2, offset, anchor_1, anchor_2);
+        }
+        JointKind::Prismatic => {
+            let axis = body_1.rotation * joint.axis_1;
+            let along = offset.dot(axis);
+
+            // removes the offset across the axis and the offset along it past the limits
+            let target = match joint.limits {
+                Some(limits) => along.clamp(limits.min, limits.max),
+                None => along,
+            };
+
+            let correction = offset - axis * target;
+            apply_positional_correction(body_1, body_2, correction, anchor_1, anchor_2);
+        }
+        JointKind::Distance { rest_length } => {
+            let distance = offset.length();
+
+            let target = match joint.limits {
+                Some(limits) => distance.clamp(limits.min, limits.max),
+                None => rest_length,
+            };
+
+            if distance > JOINT_TOLERANCE {
+                apply_positional_correction(body_1, body_2, offset / distance * (distance - target), anchor_1, anchor_2);
+            }
+        }
+    }
+}
+
+
+// Changes the relative velocity of the bodies along the joint axis towards the motor's target,
+// within the motor's max force
+fn apply_motor(
+    body_1: &JointBody,
+    body_2: &JointBody,
+    joint: &Joint,
+    motor: &JointMotor,
+    velocities: [&mut Option<Mut<Velocity>>; 2],
+    angular_velocities: [&mut Option<Mut<AngularVelocity>>; 2],
+) {
+    let axis = body_1.rotation * joint.axis_1;
+    let max_impulse = motor.max_force * DELTA;
+
+    match joint.kind {
+        JointKind::Revolute => {
+            let [angular_velocity_1, angular_velocity_2] = angular_velocities;
+
+            let relative = angular_velocity_2.as_ref().map_or(Vec3::ZERO, |velocity| velocity.0)
+                - angular_velocity_1.as_ref().map_or(Vec3::ZERO, |velocity| velocity.0);
+
+            let weight = body_1.angular_weight(axis) + body_2.angular_weight(axis);
+
+            if weight <= 0. {
+                return;
+            }
+
+            let impulse = ((motor.target_velocity - relative.dot(axis)) / weight).clamp(-max_impulse, max_impulse);
+
+            if let Some(angular_velocity) = angular_velocity_1 {
+                angular_velocity.0 -= body_1.inverse_inertia * axis * impulse;
+            }
+            if let Some(angular_velocity) = angular_velocity_2 {
+                angular_velocity.0 += body_2.inverse_inertia * axis * impulse;
+            }
+        }
+        JointKind::Prismatic => {
+            let [velocity_1, velocity_2] = velocities;
+
+            let relative = velocity_2.as_ref().map_or(Vec3::ZERO, |velocity| velocity.0)
+                - velocity_1.as_ref().map_or(Vec3::ZERO, |velocity| velocity.0);
+
+            let weight = (axis * axis).dot(body_1.inverse_mass + body_2.inverse_mass);
+
+            if weight <= 0. {
+                return;
+            }
+
+            let impulse = ((motor.target_velocity - relative.dot(axis)) / weight).clamp(-max_impulse, max_impulse);
+
+            if let Some(velocity) = velocity_1 {
+                velocity.0 -= axis * impulse * body_1.inverse_mass;
+            }
+            if let Some(velocity) = velocity_2 {
+                velocity.0 += axis * impulse * body_2.inverse_mass;
+            }
+        }
+        _ => {}
+    }
+}
+
+
+/// Stores the rest rotation of the new joints
+pub fn init_joints(
+    joint_q: Query<(Entity, &Joint), Without<JointRestRotation>>,
+    body_q: Query<&GlobalTransform>,
+    mut commands: Commands,
+) {
+    for (entity, joint) in joint_q.iter() {
+        let Ok([global_transform_1, global_transform_2]) = body_q.get_many([joint.body_1, joint.body_2]) else {
+            continue;
+        };
+
+        let (_, rotation_1, _) = global_transform_1.to_scale_rotation_translation();
+        let (_, rotation_2, _) = global_transform_2.to_scale_rotation_translation();
+
+        commands.entity(entity).insert(JointRestRotation(rotation_1.inverse() * rotation_2));
+    }
+}
+
+
+/// Solves the joints after the contacts.
+/// The bodies are moved to satisfy the joints and their velocities are updated with the correction
+pub fn solve_joints(
+    joint_q: Query<(&Joint, &JointRestRotation)>,
+    mut body_q: Query<(
+        &mut GlobalTransform,
+        &RigidBody,
+        Option<&mut Velocity>,
+        Option<&mut AngularVelocity>,
+        Option<&MassProperties>,
+        Option<&LockedAxes>
+    )>,
+) {
+    // the position of the bodies before solving the joints
+    let mut start_poses: HashMap<Entity, (Vec3, Quat)> = HashMap::new();
+
+    for _ in 0..JOINT_ITERATIONS {
+        for (joint, rest_rotation) in joint_q.iter() {
+            let Ok([
+                (mut global_transform_1, rigid_body_1, velocity_1, angular_velocity_1, mass_1, locked_axes_1),
+                (mut global_transform_2, rigid_body_2, velocity_2, angular_velocity_2, mass_2, locked_axes_2)
+            ]) = body_q.get_many_mut([joint.body_1, joint.body_2]) else {
+                continue;
+            };
+
+            let mut body_1 = JointBody::new(
+                &global_transform_1, rigid_body_1, mass_1, velocity_1.is_some(), angular_velocity_1.is_some(), locked_axes_1
+            );
+            let mut body_2 = JointBody::new(
+                &global_transform_2, rigid_body_2, mass_2, velocity_2.is_some(), angular_velocity_2.is_some(), locked_axes_2
+            );
+
+            start_poses.entry(joint.body_1).or_insert((body_1.center_of_mass(), body_1.rotation));
+            start_poses.entry(joint.body_2).or_insert((body_2.center_of_mass(), body_2.rotation));
+
+            solve_joint(&mut body_1, &mut body_2, joint, rest_rotation.0);
+
+            write_joint_body(&mut global_transform_1, &body_1, locked_axes_1);
+            write_joint_body(&mut global_transform_2, &body_2, locked_axes_2);
+        }
+    }
+
+    // the correction becomes part of the velocity, so the bodies don't keep drifting against the joint
+    for (entity, (start_center_of_mass, start_rotation)) in start_poses {
+        let Ok((global_transform, _, velocity, angular_velocity, mass_properties, _)) = body_q.get_mut(entity) else {
+            continue;
+        };
+
+        let (_, rotation, translation) = global_transform.to_scale_rotation_translation();
+        let center_of_mass = translation + rotation * mass_properties.map_or(Vec3::ZERO, |mass| mass.local_center_of_mass);
+
+        if let Some(mut velocity) = velocity {
+            velocity.0 += (center_of_mass - start_center_of_mass) / DELTA;
+        }
+
+        if let Some(mut angular_velocity) = angular_velocity {
+            let mut delta = rotation * start_rotation.inverse();
+
+            if delta.w < 0. {
+                delta = -delta;
+            }
+
+            angular_velocity.0 += delta.xyz() * 2. / DELTA;
+        }
+    }
+
+    // the motors act on the velocities, once per step
+    for (joint, _) in joint_q.iter() {
+        let Some(motor) = joint.motor else {
+            continue;
+        };
+
+        let Ok([
+            (global_transform_1, rigid_body_1, mut velocity_1, mut angular_velocity_1, mass_1, locked_axes_1),
+            (global_transform_2, rigid_body_2, mut velocity_2, mut angular_velocity_2, mass_2, locked_axes_2)
+        ]) = body_q.get_many_mut([joint.body_1, joint.body_2]) else {
+            continue;
+        };
+
+        let body_1 = JointBody::new(
+            &global_transform_1, rigid_body_1, mass_1, velocity_1.is_some(), angular_velocity_1.is_some(), locked_axes_1
+        );
+        let body_2 = JointBody::new(
+            &global_transform_2, rigid_body_2, mass_2, velocity_2.is_some(), angular_velocity_2.is_some(), locked_axes_2
+        );
+
+        apply_motor(
+            &body_1,
+            &body_2,
+            joint,
+            &motor,
+            [&mut velocity_1, &mut velocity_2],
+            [&mut angular_velocity_1, &mut angular_velocity_2],
+        );
+    }
+}
+
+
+// Moves the global transform to the solved position of the body, only if it changed.
+// The correction along the locked axes is discarded
+fn write_joint_body(global_transform: &mut Mut<GlobalTransform>, body: &JointBody, locked_axes: Option<&LockedAxes>) {
+    let (_, rotation, translation) = global_transform.to_scale_rotation_translation();
+
+    let mut rotation_delta = (body.rotation * rotation.inverse()).to_scaled_axis();
+    let mut translation_delta = body.translation - translation;
+
+    if let Some(locked_axes) = locked_axes {
+        rotation_delta = locked_axes.mask_rotation(rotation_delta);
+        translation_delta = locked_axes.mask_translation(translation_delta);
+    }
+
+    if rotation_delta != Vec3::ZERO {
+        rotate_global_transform(global_transform, Quat::from_scaled_axis(rotation_delta), translation);
+    }
+    if translation_delta != Vec3::ZERO {
+        translate_global_transform(global_transform, translation_delta);
+    }
+}
@@ -0,0 +1,124 @@
+use std::collections::HashSet;
+
+use bevy::prelude::*;
+use nalgebra::{Point3, Vector3};
+use parry3d::mass_properties::MassProperties as ParryMassProperties;
+
+use super::collisions::collider::ScaledShape;
+use super::{Mass, RigidBody};
+
+// the mass of the bodies whose collider has no volume, like trimeshes and heightfields, or no collider at all
+const DEFAULT_MASS: f32 = 1.;
+
+
+/// The density of a body's collider, used to compute its mass.
+/// Bodies without it have a density of 1
+#[derive(Component, Clone, Copy)]
+pub struct Density(pub f32);
+
+impl Default for Density {
+    fn default() -> Self {
+        Density(1.)
+    }
+}
+
+/// The mass, center of mass and inertia of a body.
+/// Computed from its Collider and Density, unless the body has a Mass overriding it.
+/// Compound colliders aggregate the mass properties of their parts
+#[derive(Component, Clone, Copy)]
+pub struct MassProperties {
+    pub mass: f32,
+    /// The center of mass relative to the body's position, along its local axes
+    pub local_center_of_mass: Vec3,
+    /// The inertia tensor around the center of mass, along the body's local axes
+    pub inertia: Mat3,
+}
+
+impl MassProperties {
+    pub fn inverse_mass(&self) -> f32 {
+        if self.mass > 0. { 1. / self.mass } else { 0. }
+    }
+
+    /// The center of mass in world space
+    pub fn world_center_of_mass(&self, global_transform: &GlobalTransform) -> Vec3 {
+        let (_, rotation, translation) = global_transform.to_scale_rotation_translation();
+
+        translation + rotation * self.local_center_of_mass
+    }
+
+    /// The inertia tensor along the world axes, for a body with the given rotation
+    pub fn world_inertia(&self, rotation: Quat) -> Mat3 {
+        let rotation = Mat3::from_quat(rotation);
+
+        rotation * self.inertia * rotation.transpose()
+    }
+
+    /// The inverse of the world space inertia tensor, zero if the body can't rotate
+    pub fn world_inverse_inertia(&self, rotation: Quat) -> Mat3 {
+        let inertia = self.world_inertia(rotation);
+
+        if inertia.determinant().abs() <= f32::EPSILON {
+            return Mat3::ZERO;
+        }
+
+        inertia.inverse()
+    }
+
+    // Builds the mass properties out of parry's, overriding the mass if needed
+    fn from_parry(mut mass_properties: ParryMassProperties, mass: Option<f32>) -> Self {
+        // parry returns no mass for the shapes without volume
+        let mass = mass.or((mass_properties.mass() <= 0.).then_some(DEFAULT_MASS));
+
+        if let Some(mass) = mass {
+            // the inertia is scaled with the mass, the distribution of the mass doesn't change
+            mass_properties.set_mass(mass, true);
+        }
+
+        let com = mass_properties.local_com;
+        let inertia = mass_properties.reconstruct_inertia_matrix();
+
+        MassProperties {
+            mass: mass_properties.mass(),
+            local_center_of_mass: Vec3::new(com.x, com.y, com.z),
+            inertia: Mat3::from_cols_array(&[
+                inertia[(0, 0)], inertia[(1, 0)], inertia[(2, 0)],
+                inertia[(0, 1)], inertia[(1, 1)], inertia[(2, 1)],
+                inertia[(0, 2)], inertia[(1, 2)], inertia[(2, 2)],
+            ]),
+        }
+    }
+}
+
+
+/// Computes the mass properties of the bodies whose collider, Density or Mass changed
+pub fn update_mass_properties(
+    changed_q: Query<Entity, (
+        With<RigidBody>,
+        Or<(Changed<ScaledShape>, Changed<Density>, Changed<Mass>, Without<MassProperties>)>
+    )>,
+    body_q: Query<(Option<&ScaledShape>, Option<&Density>, Option<&Mass>), With<RigidBody>>,
+    mut removed_mass: RemovedComponents<Mass>,
+    mut removed_density: RemovedComponents<Density>,
+    mut commands: Commands,
+) {
+    let dirty: HashSet<Entity> = changed_q.iter()
+        .chain(removed_mass.read())
+        .chain(removed_density.read())
+        .collect();
+
+    for entity in dirty {
+        let Ok((scaled_shape, density, mass)) = body_q.get(entity) else {
+            continue;
+        };
+
+        let density = density.copied().unwrap_or_default();
+
+        // the scaled shape, so that scaling a body changes its mass
+        let mass_properties = match scaled_shape {
+            Some(scaled_shape) => scaled_shape.shape.mass_properties(density.0),
+            None => ParryMassProperties::new(Point3::origin(), 0., Vector3::zeros()),
+        };
+
+        commands.entity(entity).insert(MassProperties::from_parry(mass_properties, mass.map(|mass| mass.0)));
+    }
+}
@@ -1,10 +1,38 @@
 
 use bevy::prelude::*;
-use collisions::{broad_phase, collider::ColliderPlugin, narrow_phase};
+use collisions::{broad_phase, update_collider_aabbs, update_scaled_shapes, collider::ColliderPlugin, narrow_phase, octree::Octree, solve_contacts, Contacts, OneWayPassing};
+use collisions::utils::{rotate_global_transform, translate_global_transform};
+use character::{move_characters, CharacterController};
+use joints::{init_joints, solve_joints};
+use fluids::apply_buoyancy;
+use fields::apply_force_fields;
+use vehicle::update_vehicles;
+use mass::{update_mass_properties, MassProperties};
 
 #[path = "./collisions/collisions.rs"]
 pub mod collisions;
 
+#[path = "./debug/debug.rs"]
+pub mod debug;
+
+#[path = "./mass/mass.rs"]
+pub mod mass;
+
+#[path = "./joints/joints.rs"]
+pub mod joints;
+
+#[path = "./character/character.rs"]
+pub mod character;
+
+#[path = "./vehicle/vehicle.rs"]
+pub mod vehicle;
+
+#[path = "./fluids/fluids.rs"]
+pub mod fluids;
+
+#[path = "./fields/fields.rs"]
+pub mod fields;
+
 pub struct PhysicsPlugin;
 
 const UPDATE_FREQUENCY: f32 = 30.;
@@ -13,12 +41,30 @@ impl Plugin for PhysicsPlugin {
     fn build(&self, app: &mut App) {
         app
             .insert_resource(Time::<Fixed>::from_hz(UPDATE_FREQUENCY.into()))
+            .init_resource::<Contacts>()
+            .init_resource::<OneWayPassing>()
+            .init_resource::<Octree>()
+            .init_resource::<DefaultDamping>()
             .add_plugins(ColliderPlugin)
             .add_systems(FixedUpdate, (
+                sync_global_transforms,
+                update_scaled_shapes,
+                update_mass_properties,
+                init_joints,
                 apply_gravity,
+                apply_springs,
+                update_vehicles,
+                apply_buoyancy,
+                apply_force_fields,
+                apply_damping,
                 apply_velocity,
+                move_characters,
+                update_collider_aabbs,
                 broad_phase,
                 narrow_phase,
+                solve_contacts,
+                solve_joints,
+                write_back_transforms,
             ).chain());
     }
 }
@@ -27,12 +73,74 @@ impl Plugin for PhysicsPlugin {
 #[derive(Component, Clone, Copy)]
 pub enum RigidBody {
     Static,
-    Dynamic
+    Dynamic,
+    /// Moved only by its Velocity, or by a CharacterController.
+    /// It isn't affected by forces or contacts, but pushes the dynamic bodies it touches
+    Kinematic
 }
 
 #[derive(Component, Clone, Copy)]
 pub struct Velocity(pub Vec3);
 
+/// The rotation speed of a body in radians per second, around each world axis.
+/// Bodies without it never rotate
+#[derive(Component, Clone, Copy, Default)]
+pub struct AngularVelocity(pub Vec3);
+
+/// Locks the selected world axes of a body, so it can't move along or rotate around them
+#[derive(Component, Clone, Copy, Default)]
+pub struct LockedAxes {
+    pub translation: BVec3,
+    pub rotation: BVec3,
+}
+
+impl LockedAxes {
+    pub fn new() -> Self {
+        LockedAxes::default()
+    }
+
+    pub fn lock_translation_x(mut self) -> Self {
+        self.translation.x = true;
+        self
+    }
+
+    pub fn lock_translation_y(mut self) -> Self {
+        self.translation.y = true;
+        self
+    }
+
+    pub fn lock_translation_z(mut self) -> Self {
+        self.translation.z = true;
+        self
+    }
+
+    pub fn lock_rotation_x(mut self) -> Self {
+        self.rotation.x = true;
+        self
+    }
+
+    pub fn lock_rotation_y(mut self) -> Self {
+        self.rotation.y = true;
+        self
+    }
+
+    pub fn lock_rotation_z(mut self) -> Self {
+        self.rotation.z = true;
+        self
+    }
+
+    /// Zeroes the components of a translation or linear velocity along the locked axes
+    pub fn mask_translation(&self, translation: Vec3) -> Vec3 {
+        Vec3::select(self.translation, Vec3::ZERO, translation)
+    }
+
+    /// Zeroes the components of a rotation vector or angular velocity around the locked axes
+    pub fn mask_rotation(&self, rotation: Vec3) -> Vec3 {
+        Vec3::select(self.rotation, Vec3::ZERO, rotation)
+    }
+}
+
+/// Overrides the mass computed from the body's collider and Density
 #[derive(Component)]
 pub struct Mass(pub f32);
 
@@ -40,40 +148,347 @@ pub const GRAVITY: f32 = 9.8;
 
 pub const DELTA: f32 = 1. / UPDATE_FREQUENCY;
 
-pub const TERMINAL_VELOCITY: f32 = 100.;
+
+/// Slows down a body's velocity exponentially at this rate per second.
+/// Overrides the DefaultDamping
+#[derive(Component, Clone, Copy)]
+pub struct LinearDamping(pub f32);
+
+/// Slows down a body's angular velocity exponentially at this rate per second.
+/// Overrides the DefaultDamping
+#[derive(Component, Clone, Copy)]
+pub struct AngularDamping(pub f32);
+
+/// The damping of the bodies without a LinearDamping or AngularDamping
+#[derive(Resource, Clone, Copy)]
+pub struct DefaultDamping {
+    pub linear: f32,
+    pub angular: f32,
+}
+
+impl Default for DefaultDamping {
+    fn default() -> Self {
+        DefaultDamping {
+            linear: 0.1,
+            angular: 0.1,
+        }
+    }
+}
+
+/// The maximum speed of a body, in any direction
+#[derive(Component, Clone, Copy)]
+pub struct MaxSpeed(pub f32);
+
+
+/// A spring pulling a body's anchor towards another body or a fixed world point.
+/// The anchors are in the local space of each body
+#[derive(Component, Clone, Copy)]
+pub struct Spring {
+    pub body: Entity,
+    pub anchor: Vec3,
+    pub target: SpringTarget,
+    pub rest_length: f32,
+    pub stiffness: f32,
+    /// Slows down the stretching and compression of the spring
+    pub damping: f32,
+}
+
+/// What the other end of a Spring is attached to
+#[derive(Clone, Copy)]
+pub enum SpringTarget {
+    Body { entity: Entity, anchor: Vec3 },
+    World(Vec3),
+}
+
+impl Spring {
+    /// A spring between the centers of two bodies
+    pub fn between(body_1: Entity, body_2: Entity, rest_length: f32, stiffness: f32, damping: f32) -> Self {
+        Spring {
+            body: body_1,
+            anchor: Vec3::ZERO,
+            target: SpringTarget::Body { entity: body_2, anchor: Vec3::ZERO },
+            rest_length,
+            stiffness,
+            damping,
+        }
+    }
+
+    /// A spring between the center of a body and a world point
+    pub fn to_world(body: Entity, point: Vec3, rest_length: f32, stiffness: f32, damping: f32) -> Self {
+        Spring {
+            body,
+            anchor: Vec3::ZERO,
+            target: SpringTarget::World(point),
+            rest_length,
+            stiffness,
+            damping,
+        }
+    }
+
+    /// Sets the anchor on the body and, if attached to another body, the anchor on it
+    pub fn with_anchors(mut self, anchor: Vec3, target_anchor: Vec3) -> Self {
+        self.anchor = anchor;
+
+        if let SpringTarget::Body { anchor, .. } = &mut self.target {
+            *anchor = target_anchor;
+        }
+
+        self
+    }
+}
 
 
 
 fn apply_gravity(
-    mut query: Query<(&mut RigidBody, &mut Velocity, &Mass)>
+    mut query: Query<(&mut RigidBody, &mut Velocity)>
 ) {
-    for (rigid_body, mut velocity, mass) in query.iter_mut() {
+    for (rigid_body, mut velocity) in query.iter_mut() {
         
         match *rigid_body {
-            RigidBody::Static => {}
+            RigidBody::Static | RigidBody::Kinematic => {}
             RigidBody::Dynamic => {
-                velocity.0.y -= mass.0 / GRAVITY;
+                // the same acceleration for every body, whatever its mass
+                velocity.0.y -= GRAVITY * DELTA;
+            }
+        }
+    }
 
-                if velocity.0.y <= -TERMINAL_VELOCITY {
-                    velocity.0.y = -TERMINAL_VELOCITY;
-                }
+}
+
+/// Applies the force of the springs to the velocities of their bodies
+fn apply_springs(
+    spring_q: Query<&Spring>,
+    mut body_q: Query<(
+        &GlobalTransform,
+        &RigidBody,
+        Option<&mut Velocity>,
+        Option<&mut AngularVelocity>,
+        Option<&MassProperties>
+    )>,
+) {
+    for spring in spring_q.iter() {
+        let Ok(body) = body_q.get(spring.body) else {
+            continue;
+        };
+        let (point_1, point_velocity_1) = spring_point(body, spring.anchor);
+
+        let (point_2, point_velocity_2) = match spring.target {
+            SpringTarget::Body { entity, anchor } => match body_q.get(entity) {
+                Ok(target) => spring_point(target, anchor),
+                Err(_) => continue,
+            },
+            SpringTarget::World(point) => (point, Vec3::ZERO),
+        };
+
+        let offset = point_2 - point_1;
+        let length = offset.length();
+
+        if length <= f32::EPSILON {
+            continue;
+        }
+
+        let direction = offset / length;
 
+        // pulls the anchors together when stretched, apart when compressed
+        let stretch = length - spring.rest_length;
+        let stretch_speed = (point_velocity_2 - point_velocity_1).dot(direction);
+
+        let force = direction * (spring.stiffness * stretch + spring.damping * stretch_speed);
+
+        if let Ok(body) = body_q.get_mut(spring.body) {
+            apply_spring_force(body, point_1, force);
+        }
+
+        if let SpringTarget::Body { entity, .. } = spring.target {
+            if let Ok(target) = body_q.get_mut(entity) {
+                apply_spring_force(target, point_2, -force);
             }
         }
     }
+}
+
+
+// The world position of a body's anchor and its velocity
+fn spring_point(
+    (global_transform, _, velocity, angular_velocity, mass_properties): (
+        &GlobalTransform,
+        &RigidBody,
+        Option<&Velocity>,
+        Option<&AngularVelocity>,
+        Option<&MassProperties>
+    ),
+    anchor: Vec3,
+) -> (Vec3, Vec3) {
+    let point = global_transform.transform_point(anchor);
+
+    let center_of_mass = match mass_properties {
+        Some(mass_properties) => mass_properties.world_center_of_mass(global_transform),
+        None => global_transform.translation(),
+    };
+
+    let linear = velocity.map_or(Vec3::ZERO, |velocity| velocity.0);
+    let angular = angular_velocity.map_or(Vec3::ZERO, |angular_velocity| angular_velocity.0);
+
+    (point, linear + angular.cross(point - center_of_mass))
+}
+
+
+// Applies a force at a world point of a body for one tick
+fn apply_spring_force(
+    (global_transform, rigid_body, velocity, angular_velocity, mass_properties): (
+        &GlobalTransform,
+        &RigidBody,
+        Option<Mut<Velocity>>,
+        Option<Mut<AngularVelocity>>,
+        Option<&MassProperties>
+    ),
+    point: Vec3,
+    force: Vec3,
+) {
+    let (RigidBody::Dynamic, Some(mass_properties)) = (rigid_body, mass_properties) else {
+        return;
+    };
+
+    if let Some(mut velocity) = velocity {
+        velocity.0 += force * mass_properties.inverse_mass() * DELTA;
+    }
+
+    if let Some(mut angular_velocity) = angular_velocity {
+        let (_, rotation, _) = global_transform.to_scale_rotation_translation();
+        let torque = (point - mass_properties.world_center_of_mass(global_transform)).cross(force);
+
+        angular_velocity.0 += mass_properties.world_inverse_inertia(rotation) * torque * DELTA;
+    }
+}
+
+
+/// Damps the velocities of the dynamic bodies and clamps them to their MaxSpeed
+fn apply_damping(
+    mut query: Query<(
+        &RigidBody,
+        &mut Velocity,
+        Option<&mut AngularVelocity>,
+        Option<&LinearDamping>,
+        Option<&AngularDamping>,
+        Option<&MaxSpeed>
+    )>,
+    default_damping: Res<DefaultDamping>,
+) {
+    for (rigid_body, mut velocity, angular_velocity, linear_damping, angular_damping, max_speed) in query.iter_mut() {
+        let RigidBody::Dynamic = rigid_body else {
+            continue;
+        };
+
+        let linear_damping = linear_damping.map_or(default_damping.linear, |damping| damping.0);
+        velocity.0 *= (-linear_damping * DELTA).exp();
 
+        if let Some(max_speed) = max_speed {
+            velocity.0 = velocity.0.clamp_length_max(max_speed.0);
+        }
+
+        if let Some(mut angular_velocity) = angular_velocity {
+            let angular_damping = angular_damping.map_or(default_damping.angular, |damping| damping.0);
+            angular_velocity.0 *= (-angular_damping * DELTA).exp();
+        }
+    }
 }
 
+
 fn apply_velocity(
-    mut query: Query<(&mut RigidBody, &mut Transform, &Velocity)>
+    mut query: Query<(
+        &mut RigidBody, 
+        &mut GlobalTransform, 
+        &mut Velocity, 
+        Option<&mut AngularVelocity>, 
+        Option<&MassProperties>,
+        Option<&LockedAxes>
+    ), Without<CharacterController>>
 ) {
-    for (rigid_body, mut tranform, velocity) in query.iter_mut() {
+    for (rigid_body, mut global_transform, mut velocity, mut angular_velocity, mass_properties, locked_axes) in query.iter_mut() {
         
         match *rigid_body {
             RigidBody::Static => {}
-            RigidBody::Dynamic => {
-                tranform.translation += velocity.0 * DELTA;
+            RigidBody::Dynamic | RigidBody::Kinematic => {
+                // the forces applied along the locked axes are discarded
+                if let Some(locked_axes) = locked_axes {
+                    velocity.0 = locked_axes.mask_translation(velocity.0);
+
+                    if let Some(angular_velocity) = angular_velocity.as_mut() {
+                        angular_velocity.0 = locked_axes.mask_rotation(angular_velocity.0);
+                    }
+                }
+
+                // the bodies rotate around their center of mass
+                if let Some(angular_velocity) = angular_velocity.filter(|angular_velocity| angular_velocity.0 != Vec3::ZERO) {
+                    let pivot = match mass_properties {
+                        Some(mass_properties) => mass_properties.world_center_of_mass(&global_transform),
+                        None => global_transform.translation(),
+                    };
+
+                    rotate_global_transform(&mut global_transform, Quat::from_scaled_axis(angular_velocity.0 * DELTA), pivot);
+                }
+
+                translate_global_transform(&mut global_transform, velocity.0 * DELTA);
+            }
+        }
+    }
+}
+
+
+/// Updates the world space transform of the bodies from their local Transform.
+/// The physics step works on GlobalTransform, which is otherwise only propagated once per frame
+fn sync_global_transforms(
+    body_q: Query<(Entity, &Transform, Option<&Parent>), With<RigidBody>>,
+    mut global_q: Query<&mut GlobalTransform>,
+) {
+    for (entity, transform, parent) in body_q.iter() {
+        let global_transform = match parent.and_then(|parent| global_q.get(parent.get()).ok()) {
+            Some(parent_global) => parent_global.mul_transform(*transform),
+            None => GlobalTransform::from(*transform),
+        };
+
+        if let Ok(mut body_global) = global_q.get_mut(entity) {
+            body_global.set_if_neq(global_transform);
+        }
+    }
+}
+
+
+// the minimum distance a body has to move for its Transform to be updated
+const WRITE_BACK_TOLERANCE: f32 = 1e-6;
+
+/// Writes the world space position and rotation of the moving bodies back to their local Transform,
+/// relative to their parent if they have one
+fn write_back_transforms(
+    mut body_q: Query<(Entity, &RigidBody, &mut Transform, Option<&Parent>), Changed<GlobalTransform>>,
+    global_q: Query<&GlobalTransform>,
+) {
+    for (entity, rigid_body, mut transform, parent) in body_q.iter_mut() {
+        let (RigidBody::Dynamic | RigidBody::Kinematic) = rigid_body else {
+            continue;
+        };
+
+        let Ok(global_transform) = global_q.get(entity) else {
+            continue;
+        };
+
+        let (translation, rotation) = match parent.and_then(|parent| global_q.get(parent.get()).ok()) {
+            Some(parent_global) => {
+                let local = global_transform.reparented_to(parent_global);
+                (local.translation, local.rotation)
+            }
+            None => {
+                let (_, rotation, translation) = global_transform.to_scale_rotation_translation();
+                (translation, rotation)
             }
+        };
+
+        // the physics step doesn't scale the bodies, so the scale is left untouched
+        if !transform.translation.abs_diff_eq(translation, WRITE_BACK_TOLERANCE) {
+            transform.translation = translation;
+        }
+        if !transform.rotation.abs_diff_eq(rotation, WRITE_BACK_TOLERANCE) {
+            transform.rotation = rotation;
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,302 @@
+use bevy::prelude::*;
+use parry3d::query::Ray;
+
+use super::collisions::collider::{CollisionLayers, ScaledShape, Sensor};
+use super::collisions::octree::Octree;
+use super::collisions::utils::global_transform_to_isometry;
+use super::mass::MassProperties;
+use super::{AngularVelocity, RigidBody, Velocity, DELTA};
+
+// below this forward speed the brakes stop pushing, so the vehicle doesn't jitter back and forth
+const BRAKE_MIN_SPEED: f32 = 0.1;
+
+
+/// Drives a dynamic chassis body on wheels modeled as suspension rays cast against the world.
+/// The chassis faces its local -Z axis and needs a Velocity, an AngularVelocity and a Collider
+#[derive(Component, Clone)]
+pub struct Vehicle {
+    pub wheels: Vec<Wheel>,
+    /// The engine input set by the game, from -1 (reverse) to 1
+    pub throttle: f32,
+    /// The brake input set by the game, from 0 to 1
+    pub brake: f32,
+    /// The steering input set by the game, from -1 (right) to 1 (left)
+    pub steering: f32,
+    /// The angle of the steered wheels at full steering, in radians
+    pub max_steering_angle: f32,
+    /// The torque of the engine on each driven wheel at full throttle
+    pub engine_torque: f32,
+    /// The torque of the brakes on each wheel at full brake
+    pub brake_torque: f32,
+}
+
+impl Vehicle {
+    pub fn new(wheels: Vec<Wheel>) -> Self {
+        Vehicle {
+            wheels,
+            throttle: 0.,
+            brake: 0.,
+            steering: 0.,
+            max_steering_angle: 30_f32.to_radians(),
+            engine_torque: 300.,
+            brake_torque: 500.,
+        }
+    }
+}
+
+/// A wheel of a Vehicle
+#[derive(Clone)]
+pub struct Wheel {
+    /// Where the suspension is attached, in the chassis' local space
+    pub anchor: Vec3,
+    pub radius: f32,
+    /// The length of the suspension when it isn't compressed
+    pub rest_length: f32,
+    pub stiffness: f32,
+    pub damping: f32,
+    pub friction: TireFriction,
+    /// If the wheel turns with the steering
+    pub steered: bool,
+    /// If the engine drives the wheel
+    pub driven: bool,
+    /// How much the suspension is compressed, updated each tick
+    pub compression: f32,
+    /// The rotation of the wheel around its axle, updated each tick, useful to animate the wheel
+    pub spin: f32,
+    /// Where the wheel touches the ground, None if it's in the air
+    pub contact: Option<WheelContact>,
+}
+
+impl Wheel {
+    pub fn new(anchor: Vec3, radius: f32) -> Self {
+        Wheel {
+            anchor,
+            radius,
+            rest_length: 0.3,
+            stiffness: 60.,
+            damping: 8.,
+            friction: TireFriction::default(),
+            steered: false,
+            driven: false,
+            compression: 0.,
+            spin: 0.,
+            contact: None,
+        }
+    }
+
+    pub fn steered(mut self) -> Self {
+        self.steered = true;
+        self
+    }
+
+    pub fn driven(mut self) -> Self {
+        self.driven = true;
+        self
+    }
+
+    pub fn with_suspension(mut self, rest_length: f32, stiffness: f32, damping: f32) -> Self {
+        self.rest_length = rest_length;
+        self.stiffness = stiffness;
+        self.damping = damping;
+        self
+    }
+
+    pub fn with_friction(mut self, friction: TireFriction) -> Self {
+        self.friction = friction;
+        self
+    }
+}
+
+/// The point where a Wheel touches the ground
+#[derive(Clone, Copy)]
+pub struct WheelContact {
+    pub point: Vec3,
+    pub normal: Vec3,
+    pub entity: Entity,
+}
+
+/// The grip of a tire depending on how much it slips.
+/// The grip grows up to the peak and then falls to the sliding grip,
+/// each grip being the fraction of the load on the wheel the tire can push with
+#[derive(Clone, Copy)]
+pub struct TireFriction {
+    /// The slip at the peak grip, the sideways speed over the speed of the tire
+    pub peak_slip: f32,
+    pub peak_grip: f32,
+    /// The grip when the tire fully slides sideways
+    pub sliding_grip: f32,
+}
+
+impl Default for TireFriction {
+    fn default() -> Self {
+        TireFriction {
+            peak_slip: 0.15,
+            peak_grip: 1.2,
+            sliding_grip: 0.8,
+        }
+    }
+}
+
+impl TireFriction {
+    /// The grip of the tire at the slip, from 0 (rolling) to 1 (sliding sideways)
+    pub fn grip(&self, slip: f32) -> f32 {
+        let slip = slip.abs();
+
+        if slip < self.peak_slip {
+            self.peak_grip * slip / self.peak_slip
+        }
+        else {
+            let sliding = ((slip - self.peak_slip) / (1. - self.peak_slip).max(f32::EPSILON)).min(1.);
+            self.peak_grip + (self.sliding_grip - self.peak_grip) * sliding
+        }
+    }
+}
+
+
+/// Applies the suspension, tire and engine forces of the wheels to the vehicles
+pub fn update_vehicles(
+    mut vehicle_q: Query<(
+        Entity,
+        &mut Vehicle,
+        &RigidBody,
+        &GlobalTransform,
+        &mut Velocity,
+        Option<&mut AngularVelocity>,
+        &MassProperties,
+        Option<&CollisionLayers>
+    )>,
+    body_q: Query<(&GlobalTransform, &ScaledShape, Option<&CollisionLayers>), Without<Sensor>>,
+    octree: Res<Octree>,
+) {
+    for (entity, mut vehicle, rigid_body, global_transform, mut velocity, mut angular_velocity, mass_properties, layers) in vehicle_q.iter_mut() {
+        let RigidBody::Dynamic = rigid_body else {
+            continue;
+        };
+
+        let (_, rotation, _) = global_transform.to_scale_rotation_translation();
+        let center_of_mass = mass_properties.world_center_of_mass(global_transform);
+        let inverse_inertia = mass_properties.world_inverse_inertia(rotation);
+
+        let down = rotation * Vec3::NEG_Y;
+        let layers = layers.copied().unwrap_or_default();
+        let steering_angle = vehicle.steering.clamp(-1., 1.) * vehicle.max_steering_angle;
+        let (throttle, brake) = (vehicle.throttle.clamp(-1., 1.), vehicle.brake.clamp(0., 1.));
+        let (engine_torque, brake_torque) = (vehicle.engine_torque, vehicle.brake_torque);
+
+        // the part of the chassis' mass each wheel carries
+        let wheel_mass = mass_properties.mass / vehicle.wheels.len().max(1) as f32;
+
+        let mut linear_impulse = Vec3::ZERO;
+        let mut angular_impulse = Vec3::ZERO;
+
+        for wheel in vehicle.wheels.iter_mut() {
+            let anchor = global_transform.transform_point(wheel.anchor);
+            let max_distance = wheel.rest_length + wheel.radius;
+
+            let Some((distance, normal, ground)) = cast_wheel(entity, &layers, anchor, down, max_distance, &body_q, &octree) else {
+                wheel.compression = 0.;
+                wheel.contact = None;
+                continue;
+            };
+
+            let point = anchor + down * distance;
+            let arm = point - center_of_mass;
+            let point_velocity = velocity.0 + angular_velocity.as_ref().map_or(Vec3::ZERO, |angular_velocity| angular_velocity.0.cross(arm));
+
+            // the suspension pushes the chassis up, harder the faster it is compressed
+            let compression = max_distance - distance;
+
+            // a wheel that just touched the ground has no previous compression to damp against
+            let compression_speed = match wheel.contact {
+                Some(_) => (compression - wheel.compression) / DELTA,
+                None => 0.,
+            };
+            let load = (wheel.stiffness * compression + wheel.damping * compression_speed).max(0.);
+
+            wheel.compression = compression;
+            wheel.contact = Some(WheelContact { point, normal, entity: ground });
+
+            // the directions the tire rolls and slides in, along the ground
+            let wheel_rotation = match wheel.steered {
+                true => rotation * Quat::from_rotation_y(steering_angle),
+                false => rotation,
+            };
+            let forward = (wheel_rotation * Vec3::NEG_Z).reject_from_normalized(normal).normalize_or_zero();
+            let side = normal.cross(forward);
+
+            let forward_speed = point_velocity.dot(forward);
+            let side_speed = point_velocity.dot(side);
+
+            wheel.spin += forward_speed / wheel.radius * DELTA;
+
+            let mut longitudinal = 0.;
+
+            if wheel.driven {
+                longitudinal += throttle * engine_torque / wheel.radius;
+            }
+
+            if brake > 0. && forward_speed.abs() > BRAKE_MIN_SPEED {
+                longitudinal -= forward_speed.signum() * brake * brake_torque / wheel.radius;
+            }
+
+            // the tire pushes against the sideways slip
+            let slip = side_speed / (forward_speed.abs() + side_speed.abs()).max(f32::EPSILON);
+            // no more than needed to stop the slip, so a parked vehicle doesn't jitter
+            let max_lateral = side_speed.abs() * wheel_mass / DELTA;
+            let lateral = (-side_speed.signum() * wheel.friction.grip(slip) * load).clamp(-max_lateral, max_lateral);
+
+            // the tire can't push harder than its grip allows
+            let tire_force = Vec2::new(longitudinal, lateral).clamp_length_max(wheel.friction.peak_grip * load);
+
+            let force = normal * load + forward * tire_force.x + side * tire_force.y;
+
+            linear_impulse += force * DELTA;
+            angular_impulse += arm.cross(force) * DELTA;
+        }
+
+        velocity.0 += linear_impulse * mass_properties.inverse_mass();
+
+        if let Some(angular_velocity) = angular_velocity.as_mut() {
+            angular_velocity.0 += inverse_inertia * angular_impulse;
+        }
+    }
+}
+
+
+// Casts the suspension ray of a wheel against the bodies along it, ignoring the chassis
+// and the bodies the chassis' layers don't interact with.
+// Returns the distance to the ground, its normal and the body hit
+fn cast_wheel(
+    chassis: Entity,
+    layers: &CollisionLayers,
+    anchor: Vec3,
+    direction: Vec3,
+    max_distance: f32,
+    body_q: &Query<(&GlobalTransform, &ScaledShape, Option<&CollisionLayers>), Without<Sensor>>,
+    octree: &Octree,
+) -> Option<(f32, Vec3, Entity)> {
+    let ray = Ray::new(
+        [anchor.x, anchor.y, anchor.z].into(),
+        [direction.x, direction.y, direction.z].into()
+    );
+
+    octree.entities_along_ray(anchor, direction, max_distance).into_iter()
+        .filter(|entity| *entity != chassis)
+        .filter_map(|entity| {
+            let (global_transform, shape, body_layers) = body_q.get(entity).ok()?;
+
+            if !layers.interacts_with(&body_layers.copied().unwrap_or_default()) {
+                return None;
+            }
+
+            let hit = shape.shape.cast_ray_and_get_normal(
+                &global_transform_to_isometry(global_transform),
+                &ray,
+                max_distance,
+                true
+            )?;
+
+            Some((hit.time_of_impact, Vec3::new(hit.normal.x, hit.normal.y, hit.normal.z), entity))
+        })
+        .min_by(|hit_1, hit_2| hit_1.0.total_cmp(&hit_2.0))
+}